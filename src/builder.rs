@@ -0,0 +1,145 @@
+// Copyright (c) 2016 Travis Watkins
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use alloc::sync::Arc;
+use std::fmt;
+
+use observer::LockObserver;
+use poison::{OnPanic, Poison};
+use raw::{FairnessMode, RawQueuedRwLock};
+use QueuedRwLock;
+
+/// Sets up a `QueuedRwLock` with any mix of the options the `with_*`
+/// constructors take one at a time. Anything left unset is the same as
+/// `QueuedRwLock::new`.
+pub struct QueuedRwLockBuilder<T> {
+    data: T,
+    fairness: FairnessMode,
+    max_readers: Option<usize>,
+    spin_count: Option<usize>,
+    on_panic: OnPanic,
+    observer: Option<Arc<dyn LockObserver>>,
+}
+
+impl<T> QueuedRwLockBuilder<T> {
+    pub fn new(data: T) -> QueuedRwLockBuilder<T> {
+        QueuedRwLockBuilder {
+            data,
+            fairness: FairnessMode::default(),
+            max_readers: None,
+            spin_count: None,
+            on_panic: OnPanic::default(),
+            observer: None,
+        }
+    }
+
+    /// Same as `QueuedRwLock::with_fairness`.
+    pub fn fairness(mut self, fairness: FairnessMode) -> QueuedRwLockBuilder<T> {
+        self.fairness = fairness;
+        self
+    }
+
+    /// Same as `QueuedRwLock::with_max_readers`, `build` panics if it's 0.
+    pub fn max_readers(mut self, max_readers: usize) -> QueuedRwLockBuilder<T> {
+        self.max_readers = Some(max_readers);
+        self
+    }
+
+    /// Same as `QueuedRwLock::with_spin_count`.
+    pub fn spin_count(mut self, spin_count: usize) -> QueuedRwLockBuilder<T> {
+        self.spin_count = Some(spin_count);
+        self
+    }
+
+    /// Same as `QueuedRwLock::with_panic_policy`.
+    pub fn on_panic(mut self, policy: OnPanic) -> QueuedRwLockBuilder<T> {
+        self.on_panic = policy;
+        self
+    }
+
+    /// Same as `QueuedRwLock::with_observer`.
+    pub fn observer(mut self, observer: Arc<dyn LockObserver>) -> QueuedRwLockBuilder<T> {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// # Panics
+    ///
+    /// Panics if `max_readers` was set to 0.
+    pub fn build(self) -> QueuedRwLock<T> {
+        QueuedRwLock {
+            inner: RawQueuedRwLock::configured(self.max_readers, self.spin_count, self.fairness, self.observer),
+            data: Poison::with_policy(self.data, self.on_panic),
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for QueuedRwLockBuilder<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("QueuedRwLockBuilder")
+            .field("data", &self.data)
+            .field("fairness", &self.fairness)
+            .field("max_readers", &self.max_readers)
+            .field("spin_count", &self.spin_count)
+            .field("on_panic", &self.on_panic)
+            .field("observer", &self.observer.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use {FairnessMode, LockObserver, OnPanic, QueuedRwLock};
+
+    #[test]
+    fn build() {
+        #[derive(Default)]
+        struct Reads(AtomicUsize);
+
+        impl LockObserver for Reads {
+            fn read_released(&self) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let reads = Arc::new(Reads::default());
+        let lock = QueuedRwLock::builder(0)
+            .fairness(FairnessMode::ReaderPreferring)
+            .max_readers(1)
+            .spin_count(0)
+            .on_panic(OnPanic::Abort)
+            .observer(reads.clone())
+            .build();
+
+        let guard = lock.read().unwrap();
+        assert!(lock.try_read().is_err());
+        drop(guard);
+        assert_eq!(reads.0.load(Ordering::Relaxed), 1);
+
+        // Readers go ahead of the queue
+        let ticket = lock.take_ticket();
+        drop(lock.try_read().unwrap());
+        ticket.cancel();
+    }
+
+    #[test]
+    fn defaults() {
+        let lock = QueuedRwLock::builder(1).build();
+        let guards: Vec<_> = (0..100).map(|_| lock.read().unwrap()).collect();
+        assert_eq!(lock.reader_count(), guards.len());
+
+        // Readers wait behind the queue
+        drop(guards);
+        let ticket = lock.take_ticket();
+        assert!(lock.try_read().is_err());
+        ticket.cancel();
+    }
+}
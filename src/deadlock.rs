@@ -0,0 +1,100 @@
+// Copyright (c) 2016 Travis Watkins
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Keeps track of which threads hold reads on which locks, so a read that
+// would wait on a writer that's waiting on this thread's own read can panic
+// instead of hanging. Only with the deadlock_detection feature in debug
+// builds, otherwise it's all no-ops.
+//
+// Read guards are Send, so one can be dropped on another thread than the
+// one that took it, which has no way of telling whose read it was. Those
+// releases are counted per lock and taken off what every thread holds on
+// it, which can miss a deadlock but never reports one that isn't there.
+
+#[cfg(all(feature = "deadlock_detection", debug_assertions, not(loom)))]
+use alloc::vec::Vec;
+#[cfg(all(feature = "deadlock_detection", debug_assertions, not(loom)))]
+use std::sync::{Mutex, PoisonError};
+#[cfg(all(feature = "deadlock_detection", debug_assertions, not(loom)))]
+use std::thread::{self, ThreadId};
+
+use raw::RawQueuedRwLock;
+
+// There's rarely more than a few reads held at once so lists beat maps
+#[cfg(all(feature = "deadlock_detection", debug_assertions, not(loom)))]
+struct Reads {
+    // The address of the lock and thread that took it once for every read
+    held: Vec<(usize, ThreadId)>,
+    // The address of the lock once for every read released by a thread
+    // that didn't take it
+    moved: Vec<usize>,
+}
+
+#[cfg(all(feature = "deadlock_detection", debug_assertions, not(loom)))]
+static READS: Mutex<Reads> = Mutex::new(Reads {
+    held: Vec::new(),
+    moved: Vec::new(),
+});
+
+#[cfg(all(feature = "deadlock_detection", debug_assertions, not(loom)))]
+fn key(lock: &RawQueuedRwLock) -> usize {
+    lock as *const RawQueuedRwLock as usize
+}
+
+#[cfg(all(feature = "deadlock_detection", debug_assertions, not(loom)))]
+fn count<T>(list: &[T], f: impl Fn(&T) -> bool) -> usize {
+    list.iter().filter(|&item| f(item)).count()
+}
+
+#[cfg(all(feature = "deadlock_detection", debug_assertions, not(loom)))]
+pub fn read_acquired(lock: &RawQueuedRwLock) {
+    let mut reads = READS.lock().unwrap_or_else(PoisonError::into_inner);
+    reads.held.push((key(lock), thread::current().id()));
+}
+
+#[cfg(all(feature = "deadlock_detection", debug_assertions, not(loom)))]
+pub fn read_released(lock: &RawQueuedRwLock) {
+    let key = key(lock);
+    let id = thread::current().id();
+    let mut reads = READS.lock().unwrap_or_else(PoisonError::into_inner);
+
+    match reads.held.iter().rposition(|&held| held == (key, id)) {
+        Some(i) => {
+            reads.held.swap_remove(i);
+        }
+        None => reads.moved.push(key),
+    }
+
+    // Once every read left is one that was moved nobody holds the lock, so
+    // start over rather than keep them around
+    if count(&reads.held, |held| held.0 == key) <= count(&reads.moved, |&moved| moved == key) {
+        reads.held.retain(|held| held.0 != key);
+        reads.moved.retain(|&moved| moved != key);
+    }
+}
+
+// Sure to hold a read even if every moved read was one of this thread's
+#[cfg(all(feature = "deadlock_detection", debug_assertions, not(loom)))]
+pub fn holds_read(lock: &RawQueuedRwLock) -> bool {
+    let key = key(lock);
+    let id = thread::current().id();
+    let reads = READS.lock().unwrap_or_else(PoisonError::into_inner);
+
+    count(&reads.held, |&held| held == (key, id)) > count(&reads.moved, |&moved| moved == key)
+}
+
+#[cfg(not(all(feature = "deadlock_detection", debug_assertions, not(loom))))]
+pub fn read_acquired(_lock: &RawQueuedRwLock) {}
+
+#[cfg(not(all(feature = "deadlock_detection", debug_assertions, not(loom))))]
+pub fn read_released(_lock: &RawQueuedRwLock) {}
+
+#[cfg(not(all(feature = "deadlock_detection", debug_assertions, not(loom))))]
+pub fn holds_read(_lock: &RawQueuedRwLock) -> bool {
+    false
+}
@@ -0,0 +1,203 @@
+// Copyright (c) 2016 Travis Watkins
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use {LockResult, QueuedRwLock, QueuedRwLockReadGuard, QueuedRwLockTicketGuard, QueuedRwLockWriteGuard};
+
+impl<T> QueuedRwLock<T> {
+    /// Like `read` but waits by suspending the task instead of the thread.
+    pub fn read_async(&self) -> QueuedRwLockReadFuture<'_, T> {
+        QueuedRwLockReadFuture {
+            lock: self,
+            key: None,
+            place: None,
+            done: false,
+        }
+    }
+
+    /// Like `write` but waits by suspending the task instead of the thread.
+    ///
+    /// The place in the queue is taken when this is called, not when the
+    /// future is first polled. Dropping the future gives that place up.
+    pub fn write_async(&self) -> QueuedRwLockWriteFuture<'_, T> {
+        QueuedRwLockWriteFuture {
+            lock: self,
+            ticket: self.inner.take_ticket(),
+            admitted: false,
+            done: false,
+        }
+    }
+}
+
+#[must_use = "futures do nothing unless polled"]
+pub struct QueuedRwLockReadFuture<'a, T: 'a> {
+    lock: &'a QueuedRwLock<T>,
+    key: Option<usize>,
+    place: Option<usize>,
+    done: bool,
+}
+
+impl<'a, T> Future for QueuedRwLockReadFuture<'a, T> {
+    type Output = LockResult<QueuedRwLockReadGuard<'a, T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        assert!(!this.done, "QueuedRwLockReadFuture polled after completion");
+
+        if this.lock.inner.poll_read(&mut this.key, &mut this.place, cx) {
+            this.done = true;
+            Poll::Ready(unsafe { QueuedRwLockReadGuard::new(this.lock) })
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<'a, T> Drop for QueuedRwLockReadFuture<'a, T> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            self.lock.inner.forget_read(key, self.place);
+        }
+    }
+}
+
+#[must_use = "futures do nothing unless polled"]
+pub struct QueuedRwLockWriteFuture<'a, T: 'a> {
+    lock: &'a QueuedRwLock<T>,
+    ticket: usize,
+    admitted: bool,
+    done: bool,
+}
+
+impl<'a, T> Future for QueuedRwLockWriteFuture<'a, T> {
+    type Output = LockResult<QueuedRwLockWriteGuard<'a, T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        assert!(!this.done, "QueuedRwLockWriteFuture polled after completion");
+
+        if this.lock.inner.poll_write(this.ticket, &mut this.admitted, cx) {
+            this.done = true;
+            let ticket = QueuedRwLockTicketGuard::new(this.lock, this.ticket);
+            Poll::Ready(unsafe { QueuedRwLockWriteGuard::new(ticket) })
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<'a, T> Drop for QueuedRwLockWriteFuture<'a, T> {
+    fn drop(&mut self) {
+        // Unlike a ticket guard we can't block here so the ticket is given up
+        // rather than waiting for its turn
+        if !self.done {
+            self.lock.inner.forget_write(self.ticket, self.admitted);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::{pin, Pin};
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+    use std::time::Duration;
+
+    use QueuedRwLock;
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    fn poll_once<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        Pin::new(future).poll(&mut Context::from_waker(&waker))
+    }
+
+    #[test]
+    fn smoke() {
+        let lock = QueuedRwLock::new(0);
+        *block_on(lock.write_async()).unwrap() = 1;
+        assert_eq!(*block_on(lock.read_async()).unwrap(), 1);
+    }
+
+    #[test]
+    fn waits_for_sync_guards() {
+        let lock = QueuedRwLock::new(0);
+        let write_guard = lock.write().unwrap();
+
+        thread::scope(|s| {
+            let reader = s.spawn(|| *block_on(lock.read_async()).unwrap());
+            let writer = s.spawn(|| *block_on(lock.write_async()).unwrap() += 1);
+
+            thread::sleep(Duration::from_millis(10));
+            drop(write_guard);
+            writer.join().unwrap();
+            reader.join().unwrap();
+        });
+
+        assert_eq!(*lock.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn write_futures_are_fifo() {
+        let lock = QueuedRwLock::new(0);
+        let mut first = lock.write_async();
+        let mut second = lock.write_async();
+
+        assert!(poll_once(&mut second).is_pending());
+        let guard = match poll_once(&mut first) {
+            Poll::Ready(guard) => guard.unwrap(),
+            Poll::Pending => panic!("first write future should be ready"),
+        };
+        assert!(poll_once(&mut second).is_pending());
+
+        drop(guard);
+        assert!(poll_once(&mut second).is_ready());
+    }
+
+    #[test]
+    fn drop_retires_ticket() {
+        let lock = QueuedRwLock::new(0);
+        let read_guard = lock.read().unwrap();
+
+        drop(lock.write_async());
+
+        // Got as far as waiting on the reader before being dropped
+        let mut pending = lock.write_async();
+        assert!(poll_once(&mut pending).is_pending());
+        drop(pending);
+        drop(lock.try_read().unwrap());
+
+        drop(read_guard);
+        assert_eq!(lock.queue_len(), 0);
+        drop(lock.write().unwrap());
+    }
+}
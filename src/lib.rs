@@ -6,75 +6,820 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-extern crate poison;
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
+#[cfg(not(any(feature = "std", test)))]
+extern crate core as std;
+extern crate alloc;
+
+#[cfg(feature = "lock_api")]
+extern crate lock_api;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+#[cfg(loom)]
+extern crate loom;
+
+// Loom's types can't be made in a const context, so under loom these are
+// plain fns
+macro_rules! const_fn {
+    ($(#[$attr:meta])* $vis:vis const fn $($rest:tt)*) => {
+        #[cfg(not(loom))]
+        $(#[$attr])* $vis const fn $($rest)*
+        #[cfg(loom)]
+        $(#[$attr])* $vis fn $($rest)*
+    };
+}
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use std::{fmt, mem};
-use std::cell::UnsafeCell;
+use std::iter::FromIterator;
+use std::mem::ManuallyDrop;
 use std::ops::{Deref, DerefMut};
-use std::sync::{LockResult, TryLockError, TryLockResult};
+use std::ptr;
+
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
 
 use poison::{Poison, PoisonGuard};
-use raw::RawQueuedRwLock;
 
+pub use builder::QueuedRwLockBuilder;
+pub use observer::LockObserver;
+pub use nopoison::{QueuedRwLockNoPoison, QueuedRwLockNoPoisonReadGuard, QueuedRwLockNoPoisonWriteGuard};
+pub use poison::{LockResult, OnPanic, PoisonCause, PoisonError, TryLockError, TryLockResult, TryReadError, TryWriteError};
+pub use raw::{FairnessMode, RawQueuedRwLock, ReadBlocked, WriteBlocked, WriteCounts};
+pub use rcu::RcuCell;
+#[cfg(feature = "std")]
+pub use reentrant::{QueuedRwLockReentrant, QueuedRwLockReentrantGuard};
+#[cfg(feature = "stats")]
+pub use stats::LockStats;
+
+mod builder;
+mod deadlock;
+mod nopoison;
+mod observer;
+mod poison;
 mod raw;
+mod rcu;
+#[cfg(feature = "std")]
+mod reentrant;
+#[cfg(feature = "stats")]
+mod stats;
+mod sync;
+
+#[cfg(feature = "async")]
+mod future;
+
+#[cfg(feature = "async")]
+pub use future::{QueuedRwLockReadFuture, QueuedRwLockWriteFuture};
+
+/// Gives the cause of poisoning, for the lock itself and for whatever a
+/// poisoned lock hands back, e.g. `Err(err) => err.poison_cause()`.
+pub trait PoisonInfo {
+    /// `None` if the lock isn't poisoned.
+    fn poison_cause(&self) -> Option<PoisonCause>;
+}
+
+impl<G: PoisonInfo> PoisonInfo for PoisonError<G> {
+    fn poison_cause(&self) -> Option<PoisonCause> {
+        self.get_ref().poison_cause()
+    }
+}
+
+impl<G: PoisonInfo> PoisonInfo for TryLockError<G> {
+    fn poison_cause(&self) -> Option<PoisonCause> {
+        match *self {
+            TryLockError::Poisoned(ref err) => err.poison_cause(),
+            TryLockError::WouldBlock => None,
+        }
+    }
+}
 
 /// RwLock that implements a FIFO queue for the write lock via ticket locks
+///
+/// Used purely for the ordering with `()` as the data, the poison flag is
+/// still kept since a panic in the critical section may have left whatever
+/// the lock stands for half done. If that doesn't matter use
+/// `QueuedRwLockNoPoison<()>`, which is no bigger than the raw lock and
+/// whose guards are a single pointer.
+///
+/// The getters for what the lock is doing, like `reader_count`, `queue_len`
+/// or a ticket's `position`, only take a snapshot. Other threads can change
+/// the answer as soon as it's returned.
 pub struct QueuedRwLock<T> {
     inner: RawQueuedRwLock,
-    data: UnsafeCell<Poison<T>>,
+    data: Poison<T>,
 }
 
+/// The names `std::sync` uses, so `use queuedrwlock::RwLock;` can stand in
+/// for `use std::sync::RwLock;`.
+///
+/// `read`, `write`, `try_read`, `try_write`, `into_inner` and `get_mut`
+/// have the same signatures as std's.
+pub type RwLock<T> = QueuedRwLock<T>;
+pub type RwLockReadGuard<'a, T> = QueuedRwLockReadGuard<'a, T>;
+pub type RwLockWriteGuard<'a, T> = QueuedRwLockWriteGuard<'a, T>;
+
 unsafe impl<T: Send> Send for QueuedRwLock<T> {}
-unsafe impl<T: Sync> Sync for QueuedRwLock<T> {}
+// Writers on other threads can swap the data out and readers on other
+// threads see it at the same time, same as std::sync::RwLock
+unsafe impl<T: Send + Sync> Sync for QueuedRwLock<T> {}
 
 impl<T> QueuedRwLock<T> {
-    pub fn new(data: T) -> QueuedRwLock<T> {
+    const_fn! {
+        pub const fn new(data: T) -> QueuedRwLock<T> {
+            QueuedRwLock {
+                inner: RawQueuedRwLock::new(),
+                data: Poison::new(data),
+            }
+        }
+    }
+
+    const_fn! {
+        /// Like `new` but lets at most `max_readers` readers hold the lock at
+        /// once, anyone past that waits for one of them to unlock.
+        ///
+        /// Nested `read_recursive` calls count towards the limit too. The
+        /// limit is kept next to the reader count, which has to be checked
+        /// anyway, so it costs nothing over `new` and a fixed one can still
+        /// be set up in a `static`.
+        ///
+        /// # Panics
+        ///
+        /// Panics if `max_readers` is 0.
+        pub const fn with_max_readers(data: T, max_readers: usize) -> QueuedRwLock<T> {
+            QueuedRwLock {
+                inner: RawQueuedRwLock::with_max_readers(max_readers),
+                data: Poison::new(data),
+            }
+        }
+    }
+
+    const_fn! {
+        /// Like `new` but sets how many times a blocked `read` or `write` spins
+        /// waiting for the lock before going to sleep.
+        ///
+        /// Spinning costs CPU time but avoids sleeping when the lock is only
+        /// held briefly, 0 turns it off.
+        pub const fn with_spin_count(data: T, spin_count: usize) -> QueuedRwLock<T> {
+            QueuedRwLock {
+                inner: RawQueuedRwLock::with_spin_count(spin_count),
+                data: Poison::new(data),
+            }
+        }
+    }
+
+    const_fn! {
+        /// Like `new` but with a different policy for who goes first when
+        /// readers and writers are both waiting.
+        pub const fn with_fairness(data: T, fairness: FairnessMode) -> QueuedRwLock<T> {
+            QueuedRwLock {
+                inner: RawQueuedRwLock::with_fairness(fairness),
+                data: Poison::new(data),
+            }
+        }
+    }
+
+    const_fn! {
+        /// Like `new` but lets up to `readers_per_writer` readers go ahead of
+        /// a queued writer before new readers have to wait for it, see
+        /// `FairnessMode::WriteRatio`.
+        pub const fn with_write_ratio(data: T, readers_per_writer: usize) -> QueuedRwLock<T> {
+            QueuedRwLock::with_fairness(data, FairnessMode::WriteRatio(readers_per_writer))
+        }
+    }
+
+    const_fn! {
+        /// Like `new` but with a choice of what a panic while the write lock
+        /// is held does, see `OnPanic`.
+        pub const fn with_panic_policy(data: T, policy: OnPanic) -> QueuedRwLock<T> {
+            QueuedRwLock {
+                inner: RawQueuedRwLock::new(),
+                data: Poison::with_policy(data, policy),
+            }
+        }
+    }
+
+    /// Makes a lock the thread holding it can write lock again, see
+    /// `QueuedRwLockReentrant` for what that means for the data.
+    #[cfg(feature = "std")]
+    pub fn new_reentrant(data: T) -> QueuedRwLockReentrant<T> {
+        QueuedRwLockReentrant::new(data)
+    }
+
+    /// Like `new` but reports every lock, unlock and ticket to `observer`,
+    /// e.g. to trace contention. Locks made any other way skip the reporting
+    /// entirely.
+    pub fn with_observer(data: T, observer: Arc<dyn LockObserver>) -> QueuedRwLock<T> {
         QueuedRwLock {
-            inner: RawQueuedRwLock::new(),
-            data: UnsafeCell::new(Poison::new(data)),
+            inner: RawQueuedRwLock::with_observer(observer),
+            data: Poison::new(data),
         }
     }
 
-    pub fn read(&self) -> LockResult<QueuedRwLockReadGuard<T>> {
+    /// Starts a `QueuedRwLockBuilder`, for setting more than one of the
+    /// options the other constructors take.
+    pub fn builder(data: T) -> QueuedRwLockBuilder<T> {
+        QueuedRwLockBuilder::new(data)
+    }
+
+    /// Shorthand for `Arc::new(QueuedRwLock::new(data))`, for use with
+    /// `read_arc` and `write_arc`.
+    pub fn new_arc(data: T) -> Arc<QueuedRwLock<T>> {
+        Arc::new(QueuedRwLock::new(data))
+    }
+
+    /// With the `deadlock_detection` feature in a debug build this panics,
+    /// rather than hanging, if the thread already holds a read lock that a
+    /// waiting writer is held up by. See `read_recursive`.
+    pub fn read(&self) -> LockResult<QueuedRwLockReadGuard<'_, T>> {
         self.inner.read();
         unsafe { QueuedRwLockReadGuard::new(self) }
     }
 
-    pub fn try_read(&self) -> TryLockResult<QueuedRwLockReadGuard<T>> {
+    /// Same as `read`, along with whether it went to sleep waiting for a
+    /// writer or for room among the readers, e.g. to tune how much gets done
+    /// under each lock. Only a hint, a read that got in while spinning or
+    /// only had to take the slow path doesn't count.
+    pub fn read_contended(&self) -> LockResult<(QueuedRwLockReadGuard<'_, T>, bool)> {
+        let waited = self.inner.read_contended();
+        poison::map_result(unsafe { QueuedRwLockReadGuard::new(self) }, |guard| (guard, waited))
+    }
+
+    /// Like `read` but doesn't wait behind queued writers as long as the lock
+    /// is already read locked.
+    ///
+    /// This lets a thread that already holds a read guard take another one
+    /// without deadlocking against a writer queued in between. Using it where
+    /// that isn't needed can keep writers waiting for as long as readers
+    /// keep overlapping.
+    pub fn read_recursive(&self) -> LockResult<QueuedRwLockReadGuard<'_, T>> {
+        self.inner.read_recursive();
+        unsafe { QueuedRwLockReadGuard::new(self) }
+    }
+
+    /// Like `read` but goes ahead of queued writers, as if readers were
+    /// preferred for this one call. Only a writer that's already had its
+    /// turn is waited for.
+    ///
+    /// Meant for the odd latency sensitive read, used everywhere it starves
+    /// writers the same as `FairnessMode::ReaderPreferring`.
+    pub fn read_impatient(&self) -> LockResult<QueuedRwLockReadGuard<'_, T>> {
+        self.inner.read_impatient();
+        unsafe { QueuedRwLockReadGuard::new(self) }
+    }
+
+    pub fn try_read(&self) -> TryLockResult<QueuedRwLockReadGuard<'_, T>> {
         if self.inner.try_read() {
-            Ok(try!(unsafe { QueuedRwLockReadGuard::new(self) }))
+            Ok(unsafe { QueuedRwLockReadGuard::new(self) }?)
+        } else {
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    /// Like `try_read` but says whether it failed because of an active
+    /// writer or only queued ones, e.g. to decide between spinning for a
+    /// bit and backing off.
+    pub fn try_read_reason(&self) -> Result<QueuedRwLockReadGuard<'_, T>, TryReadError<QueuedRwLockReadGuard<'_, T>>> {
+        match self.inner.try_read_reason() {
+            Ok(()) => Ok(unsafe { QueuedRwLockReadGuard::new(self) }?),
+            Err(blocked) => Err(TryReadError::from_blocked(blocked)),
+        }
+    }
+
+    /// Like `try_read` but yields to other threads and tries again up to
+    /// `max_yields` times before giving up with `WouldBlock`, for when
+    /// blocking the thread on the lock isn't wanted.
+    #[cfg(feature = "std")]
+    pub fn read_yield(&self, max_yields: usize) -> TryLockResult<QueuedRwLockReadGuard<'_, T>> {
+        for _ in 0..max_yields {
+            match self.try_read() {
+                Err(TryLockError::WouldBlock) => std::thread::yield_now(),
+                result => return result,
+            }
+        }
+
+        self.try_read()
+    }
+
+    /// Like `read` but gives up with `WouldBlock` once `dur` has elapsed.
+    #[cfg(feature = "std")]
+    pub fn read_timeout(&self, dur: Duration) -> TryLockResult<QueuedRwLockReadGuard<'_, T>> {
+        match Instant::now().checked_add(dur) {
+            Some(deadline) => self.try_read_until(deadline),
+            None => Ok(self.read()?),
+        }
+    }
+
+    /// Like `read_timeout` but with a deadline, e.g. one shared by several
+    /// locks. A deadline that's already passed is the same as `try_read`.
+    #[cfg(feature = "std")]
+    pub fn try_read_until(&self, deadline: Instant) -> TryLockResult<QueuedRwLockReadGuard<'_, T>> {
+        if self.inner.read_until(deadline) {
+            Ok(unsafe { QueuedRwLockReadGuard::new(self) }?)
         } else {
             Err(TryLockError::WouldBlock)
         }
     }
 
-    pub fn take_ticket(&self) -> QueuedRwLockTicketGuard<T> {
+    pub fn take_ticket(&self) -> QueuedRwLockTicketGuard<'_, T> {
         let ticket = self.inner.take_ticket();
         QueuedRwLockTicketGuard::new(self, ticket)
     }
 
-    pub fn write(&self) -> LockResult<QueuedRwLockWriteGuard<T>> {
+    /// Like `take_ticket` but the ticket only waits for its turn until
+    /// `deadline`, after which `write` gives up with `WouldBlock`.
+    #[cfg(feature = "std")]
+    pub fn take_ticket_until(&self, deadline: Instant) -> QueuedRwLockTimedTicketGuard<'_, T> {
+        QueuedRwLockTimedTicketGuard {
+            ticket: self.take_ticket(),
+            deadline,
+        }
+    }
+
+    pub fn write(&self) -> LockResult<QueuedRwLockWriteGuard<'_, T>> {
         let ticket = self.take_ticket();
         ticket.write()
     }
 
-    pub fn try_write(&self) -> TryLockResult<QueuedRwLockWriteGuard<T>> {
-        if self.inner.try_write_skip_queue() {
-            // dummy ticket for write guard
-            let ticket = QueuedRwLockTicketGuard::new(self, 0);
-            Ok(try!(unsafe { QueuedRwLockWriteGuard::new(ticket) }))
+    /// Same as `write`, along with whether it had to wait for its turn or
+    /// for readers to leave, like `read_contended`.
+    pub fn write_contended(&self) -> LockResult<(QueuedRwLockWriteGuard<'_, T>, bool)> {
+        let ticket = self.take_ticket();
+        let waited = self.inner.write_contended(ticket.ticket);
+        poison::map_result(unsafe { QueuedRwLockWriteGuard::new(ticket) }, |guard| (guard, waited))
+    }
+
+    pub fn try_write(&self) -> TryLockResult<QueuedRwLockWriteGuard<'_, T>> {
+        match self.inner.try_write_reason() {
+            Ok(ticket) => {
+                let ticket = QueuedRwLockTicketGuard::new(self, ticket);
+                Ok(unsafe { QueuedRwLockWriteGuard::new(ticket) }?)
+            }
+            Err(_) => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Like `write` but goes ahead of every writer already queued, waiting
+    /// only for the writer or readers that hold the lock now.
+    ///
+    /// Meant for the odd urgent write, e.g. on shutdown. Normal writers wait
+    /// for as long as priority writers keep coming, and so do new readers.
+    pub fn write_priority(&self) -> LockResult<QueuedRwLockWriteGuard<'_, T>> {
+        let ticket = self.inner.write_priority();
+        let ticket = QueuedRwLockTicketGuard::new(self, ticket);
+        unsafe { QueuedRwLockWriteGuard::new(ticket) }
+    }
+
+    /// Like `try_write` but says what got in the way, e.g. to back off for
+    /// longer when there's a queue than when readers are about to leave.
+    pub fn try_write_reason(&self) -> Result<QueuedRwLockWriteGuard<'_, T>, TryWriteError<QueuedRwLockWriteGuard<'_, T>>> {
+        match self.inner.try_write_reason() {
+            Ok(ticket) => Ok(unsafe { QueuedRwLockWriteGuard::new(QueuedRwLockTicketGuard::new(self, ticket)) }?),
+            Err(blocked) => Err(TryWriteError::from_blocked(blocked)),
+        }
+    }
+
+    /// Like `read` but the guard holds on to a clone of the `Arc` instead of
+    /// borrowing, so it can be moved anywhere, e.g. into a spawned thread.
+    pub fn read_arc(self: &Arc<Self>) -> LockResult<QueuedRwLockArcReadGuard<T>>
+        where T: 'static
+    {
+        poison::map_result(self.arc_lock().read(), |guard| {
+            QueuedRwLockArcReadGuard { guard, lock: self.clone() }
+        })
+    }
+
+    /// Like `write` but the guard holds on to a clone of the `Arc` instead of
+    /// borrowing, the same as `read_arc`.
+    pub fn write_arc(self: &Arc<Self>) -> LockResult<QueuedRwLockArcWriteGuard<T>>
+        where T: 'static
+    {
+        poison::map_result(self.arc_lock().write(), |guard| {
+            QueuedRwLockArcWriteGuard { guard, lock: self.clone() }
+        })
+    }
+
+    // Only good for a guard stored next to a clone of the Arc, which keeps
+    // the lock alive for as long as the guard
+    fn arc_lock(self: &Arc<Self>) -> &'static QueuedRwLock<T>
+        where T: 'static
+    {
+        unsafe { &*Arc::as_ptr(self) }
+    }
+
+    /// Like `read` but hands back the guard even if the lock is poisoned, for
+    /// when the data is good to use either way.
+    ///
+    /// The lock stays poisoned, `is_poisoned` still sees it.
+    pub fn read_ignore_poison(&self) -> QueuedRwLockReadGuard<'_, T> {
+        self.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Like `write` but hands back the guard even if the lock is poisoned.
+    ///
+    /// The lock stays poisoned, `is_poisoned` still sees it.
+    pub fn write_ignore_poison(&self) -> QueuedRwLockWriteGuard<'_, T> {
+        self.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Like `try_read` but panics instead of failing, the same as
+    /// `RefCell::borrow`. Meant for code that knows nobody else is using the
+    /// lock.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock is write locked, has writers queued or is poisoned.
+    #[track_caller]
+    pub fn borrow(&self) -> QueuedRwLockReadGuard<'_, T> {
+        match self.try_read() {
+            Ok(guard) => guard,
+            Err(TryLockError::WouldBlock) => panic!("QueuedRwLock already locked, can't borrow for reading"),
+            Err(TryLockError::Poisoned(_)) => panic!("QueuedRwLock poisoned, can't borrow for reading"),
+        }
+    }
+
+    /// Like `try_write` but panics instead of failing, the same as
+    /// `RefCell::borrow_mut`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock is locked or queued for at all, or is poisoned.
+    #[track_caller]
+    pub fn borrow_mut(&self) -> QueuedRwLockWriteGuard<'_, T> {
+        match self.try_write() {
+            Ok(guard) => guard,
+            Err(TryLockError::WouldBlock) => panic!("QueuedRwLock already locked, can't borrow for writing"),
+            Err(TryLockError::Poisoned(_)) => panic!("QueuedRwLock poisoned, can't borrow for writing"),
+        }
+    }
+
+    /// Like `write` but gives up with `WouldBlock` once `dur` has elapsed.
+    ///
+    /// A timed out write still gives up its place in the queue so writers
+    /// behind it are not held up.
+    #[cfg(feature = "std")]
+    pub fn write_timeout(&self, dur: Duration) -> TryLockResult<QueuedRwLockWriteGuard<'_, T>> {
+        match Instant::now().checked_add(dur) {
+            Some(deadline) => self.try_write_until(deadline),
+            None => Ok(self.write()?),
+        }
+    }
+
+    /// Like `write_timeout` but with a deadline, e.g. one shared by several
+    /// locks. A deadline that's already passed is the same as `try_write`.
+    #[cfg(feature = "std")]
+    pub fn try_write_until(&self, deadline: Instant) -> TryLockResult<QueuedRwLockWriteGuard<'_, T>> {
+        let ticket = self.inner.take_ticket();
+        if self.inner.write_until(ticket, deadline) {
+            let ticket = QueuedRwLockTicketGuard::new(self, ticket);
+            Ok(unsafe { QueuedRwLockWriteGuard::new(ticket) }?)
         } else {
             Err(TryLockError::WouldBlock)
         }
     }
 
+    /// Like `write` but only the wait for readers to leave once it's this
+    /// write's turn is timed, the wait in the queue isn't. Gives up with
+    /// `WouldBlock` if readers are still there once `dur` has elapsed,
+    /// letting readers and the next writer in.
+    ///
+    /// Same as `take_ticket().write_dequeued().finish_timeout(dur)`.
+    #[cfg(feature = "std")]
+    pub fn write_drain_timeout(&self, dur: Duration) -> TryLockResult<QueuedRwLockWriteGuard<'_, T>> {
+        self.take_ticket().write_dequeued().finish_timeout(dur)
+    }
+
+    /// Takes a read lock that can later be upgraded to a write lock.
+    ///
+    /// Only one upgradable read lock can be held at a time and it waits its
+    /// turn in the writer queue, holding off later writers until it's dropped.
+    pub fn upgradable_read(&self) -> LockResult<QueuedRwLockUpgradableReadGuard<'_, T>> {
+        let ticket = self.inner.upgradable_read();
+        unsafe { QueuedRwLockUpgradableReadGuard::new(self, ticket) }
+    }
+
+    /// Runs `f` under a read lock and returns what it returns.
+    ///
+    /// If the lock is poisoned `f` still runs, its result comes back inside
+    /// the `PoisonError` the same way the guard does from `read`.
+    pub fn read_with<R, F>(&self, f: F) -> LockResult<R>
+        where F: FnOnce(&T) -> R
+    {
+        poison::map_result(self.read(), |guard| f(&guard))
+    }
+
+    /// Waits for `pred` to hold for the data and returns the read guard it
+    /// held under, checking again after every write.
+    ///
+    /// The check is done under a read lock, if it fails the lock is let go
+    /// until the next write is done. A write that comes in between can't be
+    /// missed. Poisoning works the same as `read`, `pred` still gets a look
+    /// at the data.
+    ///
+    /// With `set_notify_on_write(false)` only writes that call
+    /// `QueuedRwLockWriteGuard::notify_condition` lead to another check.
+    pub fn wait_until<F>(&self, mut pred: F) -> LockResult<QueuedRwLockReadGuard<'_, T>>
+        where F: FnMut(&T) -> bool
+    {
+        loop {
+            let guard = self.read();
+
+            // Only a writer notifies, and nobody can be writing while we hold
+            // a read lock
+            let seen = self.inner.notifications();
+            let held = match guard {
+                Ok(ref guard) => pred(guard),
+                Err(ref err) => pred(err.get_ref()),
+            };
+
+            if held {
+                return guard;
+            }
+            drop(guard);
+            self.inner.wait_for_notify(seen);
+        }
+    }
+
+    /// Like `read_with` but if the lock is poisoned `recover` gets to put
+    /// the data right under a write lock. If it returns true the poison is
+    /// cleared, either way the read is tried again.
+    ///
+    /// The recovery has to be passed in since only the caller knows how to
+    /// fix the data, and it returns whether it managed to rather than the
+    /// poison always being cleared, so data it couldn't fix isn't passed
+    /// off as good. `attempts` is how many times to recover before giving
+    /// up, in case the data can't be put right or the lock keeps getting
+    /// poisoned, which would otherwise loop forever.
+    ///
+    /// Once the attempts run out `f` is run the same as `read_with` would,
+    /// so if the lock is still poisoned `f`'s result comes back inside the
+    /// `PoisonError` rather than being lost. With `attempts` set to 0 this
+    /// is just `read_with`. A panic in `recover` carries on up as usual.
+    pub fn read_retry<R, F, G>(&self, attempts: usize, mut recover: G, f: F) -> LockResult<R>
+        where F: Fn(&T) -> R,
+              G: FnMut(&mut T) -> bool
+    {
+        for _ in 0..attempts {
+            if let Ok(guard) = self.read() {
+                return Ok(f(&guard));
+            }
+
+            // Someone may have recovered it while we waited
+            let mut guard = self.write().unwrap_or_else(PoisonError::into_inner);
+            if self.is_poisoned() && recover(&mut guard) {
+                self.clear_poison();
+            }
+        }
+
+        self.read_with(f)
+    }
+
+    /// Runs `f` under a write lock and returns what it returns.
+    ///
+    /// Poisoning works the same as `read_with`, and the lock is poisoned if
+    /// `f` panics.
+    pub fn write_with<R, F>(&self, f: F) -> LockResult<R>
+        where F: FnOnce(&mut T) -> R
+    {
+        poison::map_result(self.write(), |mut guard| f(&mut guard))
+    }
+
+    /// Runs `update` under a write lock, but only if `pred` holds for the
+    /// data at that point, returning `None` otherwise.
+    ///
+    /// Both see the data under the same guard so nothing can change in
+    /// between. Poisoning works the same as `write_with`.
+    pub fn write_if<R, P, F>(&self, pred: P, update: F) -> LockResult<Option<R>>
+        where P: FnOnce(&T) -> bool,
+              F: FnOnce(&mut T) -> R
+    {
+        poison::map_result(self.write(), |mut guard| {
+            if pred(&guard) {
+                Some(update(&mut guard))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Puts `value` in the lock and returns what was there. Poisoning works
+    /// the same as `write_with`, the value is swapped either way.
+    pub fn swap(&self, value: T) -> LockResult<T> {
+        self.write_with(|data| mem::replace(data, value))
+    }
+
+    /// Swaps in what `f` makes from the current value and returns the old
+    /// one, all under the same write lock.
+    ///
+    /// If `f` panics nothing is swapped and the lock is poisoned.
+    pub fn replace_with<F>(&self, f: F) -> LockResult<T>
+        where F: FnOnce(&T) -> T
+    {
+        self.write_with(|data| {
+            let value = f(data);
+            mem::replace(data, value)
+        })
+    }
+
+    /// Same as `swap` but drops the old value.
+    pub fn store(&self, value: T) -> LockResult<()> {
+        self.write_with(|data| *data = value)
+    }
+
+    /// Runs `f` under a read lock if one can be had without waiting, the
+    /// guard is gone by the time this returns.
+    ///
+    /// Returns `None` if the lock is poisoned too, use `is_poisoned` to
+    /// tell that apart from the lock being busy.
+    pub fn peek<R, F>(&self, f: F) -> Option<R>
+        where F: FnOnce(&T) -> R
+    {
+        self.try_read().ok().map(|guard| f(&guard))
+    }
+
+    /// Maps every element of the collection behind the lock and collects the
+    /// results, all under a single read lock that's released before
+    /// returning.
+    ///
+    /// Handy when the elements need to outlive the lock, e.g. to hold on to
+    /// them across an await. For anything else iterate a read guard
+    /// directly, `for x in lock.read()?.iter()`, which doesn't allocate.
+    /// Poisoning works the same as `read_with`.
+    pub fn read_map_collect<'b, E, U, B, F>(&'b self, f: F) -> LockResult<B>
+        where &'b T: IntoIterator<Item = &'b E>,
+              E: 'b,
+              F: FnMut(&E) -> U,
+              B: FromIterator<U>
+    {
+        poison::map_result(self.read(), |guard| guard.data.into_iter().map(f).collect())
+    }
+
+    /// Checks whether a writer panicked while holding the lock, without
+    /// taking the lock or joining the queue.
+    pub fn is_poisoned(&self) -> bool {
+        self.data.is_poisoned()
+    }
+
+    /// Marks the lock as no longer poisoned.
+    ///
+    /// This doesn't take the lock, so it can be called while holding the guard
+    /// from a poison error once the data has been put right.
+    pub fn clear_poison(&self) {
+        self.data.heal();
+    }
+
+    /// Marks the lock as poisoned without panicking, e.g. when the data has
+    /// been found to be corrupt.
+    ///
+    /// Like `clear_poison` this doesn't take the lock, so it can be called
+    /// with or without holding a guard.
+    pub fn poison(&self) {
+        self.data.poison();
+    }
+
+    /// Why the lock was poisoned, `None` if it isn't.
+    pub fn poison_cause(&self) -> Option<PoisonCause> {
+        self.data.cause()
+    }
+
+    /// The error a lock call would return right now if the lock is
+    /// poisoned, without a guard in it, e.g. for logging. Like
+    /// `is_poisoned` this doesn't take the lock.
+    pub fn poison_error(&self) -> Option<PoisonError<()>> {
+        if self.is_poisoned() {
+            Some(PoisonError::new(()))
+        } else {
+            None
+        }
+    }
+
+    /// Number of readers holding the lock, including an upgradable reader.
+    pub fn reader_count(&self) -> usize {
+        self.inner.reader_count()
+    }
+
+    /// Whether a writer holds the lock or is waiting on readers to leave.
+    pub fn is_write_locked(&self) -> bool {
+        self.inner.has_writer()
+    }
+
+    /// Changes every time a write lock is taken or released, so a reader
+    /// can tell whether the data may have changed since it last looked. It's
+    /// odd while a writer holds the lock.
+    ///
+    /// It wraps around on overflow, so two equal values only mean nothing
+    /// changed if fewer than 2^63 writes could have happened in between.
+    pub fn generation(&self) -> u64 {
+        self.inner.generation()
+    }
+
+    /// How many writes have been asked for and how many finished, sample it
+    /// now and again to get write throughput.
+    ///
+    /// The difference isn't how many writes are in flight. Tickets that are
+    /// cancelled or time out, write futures dropped before they finish and
+    /// upgradable reads that are never upgraded all count as requested but
+    /// never as completed.
+    pub fn write_counts(&self) -> WriteCounts {
+        self.inner.write_counts()
+    }
+
+    /// Totals of the locks taken so far, how long writers waited and how
+    /// long the queue got.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> LockStats {
+        self.inner.stats()
+    }
+
+    /// Moving average of `queue_len`, taken each time a ticket is taken or
+    /// retired so the latest ones count the most. Smoother than `queue_len`
+    /// for deciding whether the lock is getting busier, e.g. to shed load.
+    #[cfg(feature = "stats")]
+    pub fn avg_queue_depth(&self) -> f64 {
+        self.inner.avg_queue_depth()
+    }
+
+    /// Copies the data out without taking the lock, or returns `None` if a
+    /// writer had the lock at any point while copying or it's poisoned.
+    ///
+    /// The copy is checked against the generation before and after, so a
+    /// copy torn by a writer is thrown away rather than returned. Only
+    /// worth it for small `Copy` types, anything else is better off with
+    /// `read` or `peek`.
+    pub fn optimistic_read(&self) -> Option<T>
+        where T: Copy
+    {
+        let generation = self.inner.optimistic_begin()?;
+
+        // Volatile so the copy is actually made from memory in between the
+        // two generation checks
+        let data = unsafe { ptr::read_volatile(self.data.data_ptr()) };
+
+        if self.inner.optimistic_validate(generation) && !self.is_poisoned() {
+            Some(data)
+        } else {
+            None
+        }
+    }
+
+    /// Whether releasing a write lock wakes `wait_until` to check again, on
+    /// by default. Turning it off saves waking waiters for writes that make
+    /// no difference to them, as long as the writes that do call
+    /// `QueuedRwLockWriteGuard::notify_condition`.
+    pub fn set_notify_on_write(&self, notify: bool) {
+        self.inner.set_notify_on_write(notify);
+    }
+
+    /// Whether `write` would have to wait right now, for a writer, readers
+    /// or anyone queued. Doesn't take a ticket.
+    pub fn write_would_block(&self) -> bool {
+        self.inner.write_would_block()
+    }
+
+    /// Number of writers (and upgradable readers) waiting in the queue, not
+    /// counting one that already holds the lock.
+    pub fn queue_len(&self) -> usize {
+        self.inner.queue_len()
+    }
+
+    /// Blocks until there are no readers, no writer and nobody queued,
+    /// without taking the lock itself.
+    ///
+    /// Only a snapshot, new readers or writers may have come along by the
+    /// time it returns. Take a write lock instead if that matters.
+    pub fn wait_idle(&self) {
+        self.inner.wait_idle();
+    }
+
+    /// Pointer to the data, without taking the lock.
+    ///
+    /// The pointer is always valid but it's up to the caller to make sure
+    /// nobody else is accessing the data while it's used, e.g. by holding a
+    /// guard for as long as the pointer is used. Poisoning is ignored.
+    pub fn data_ptr(&self) -> *mut T {
+        self.data.data_ptr()
+    }
+
     pub fn into_inner(self) -> LockResult<T> {
-        unsafe { self.data.into_inner().into_inner() }
+        self.data.into_inner()
     }
 
     pub fn get_mut(&mut self) -> LockResult<&mut T> {
-        poison::map_result(unsafe { &mut *self.data.get() }.lock(),
-                           |guard| { unsafe { guard.into_mut() } })
+        self.data.get_mut()
+    }
+
+    /// Like `get_mut` but ignores poisoning, for when the data is good to use
+    /// either way.
+    ///
+    /// Nothing is unchecked beyond the poison flag, `&mut self` already
+    /// rules out anyone else holding the lock. Unlike `into_inner` the lock
+    /// is left in place and stays poisoned if it was.
+    pub fn data_mut_unchecked(&mut self) -> &mut T {
+        self.data.get_mut().unwrap_or_else(PoisonError::into_inner)
     }
 }
 
@@ -92,165 +837,3376 @@ impl<T: fmt::Debug> fmt::Debug for QueuedRwLock<T> {
     }
 }
 
-#[must_use]
-pub struct QueuedRwLockReadGuard<'a, T: 'a> {
-    lock: &'a QueuedRwLock<T>,
-    data: &'a T,
-}
+/// Read locks both and compares the data, poisoned or not, so that like `Eq`
+/// says a lock is always equal to itself.
+///
+/// The lock at the lower address is taken first so two threads comparing
+/// the same pair can't deadlock, but a writer holding one of them while it
+/// waits on the other can. Comparing a lock with itself doesn't lock it.
+impl<T: PartialEq> PartialEq for QueuedRwLock<T> {
+    fn eq(&self, other: &QueuedRwLock<T>) -> bool {
+        if ptr::eq(self, other) {
+            return true;
+        }
 
-impl<'a, T> QueuedRwLockReadGuard<'a, T> {
-    unsafe fn new(lock: &'a QueuedRwLock<T>) -> LockResult<QueuedRwLockReadGuard<'a, T>> {
-        poison::map_result((*lock.data.get()).get(), |data| {
-            QueuedRwLockReadGuard {
-                lock: lock,
-                data: data,
-            }
-        })
+        let (first, second) = if (self as *const QueuedRwLock<T>) < (other as *const QueuedRwLock<T>) {
+            (self, other)
+        } else {
+            (other, self)
+        };
+
+        let first = first.read().unwrap_or_else(PoisonError::into_inner);
+        let second = second.read().unwrap_or_else(PoisonError::into_inner);
+        *first == *second
     }
 }
 
-unsafe impl<'a, T: Send> Send for QueuedRwLockReadGuard<'a, T> {}
-unsafe impl<'a, T: Sync> Sync for QueuedRwLockReadGuard<'a, T> {}
+impl<T: Eq> Eq for QueuedRwLock<T> {}
 
-impl<'a, T> Deref for QueuedRwLockReadGuard<'a, T> {
-    type Target = T;
+/// Write locks two locks at once, always in the same order whichever way
+/// round they're passed, so two threads locking the same pair can't
+/// deadlock on each other.
+///
+/// If either lock is poisoned both guards come back inside the error.
+///
+/// # Panics
+///
+/// Panics if `a` and `b` are the same lock.
+// The error is as big as the guards in it, the same as for `write`
+#[allow(clippy::result_large_err)]
+pub fn write_both<'a, A, B>(a: &'a QueuedRwLock<A>,
+                            b: &'a QueuedRwLock<B>)
+                            -> LockResult<(QueuedRwLockWriteGuard<'a, A>, QueuedRwLockWriteGuard<'a, B>)> {
+    let a_addr = a as *const QueuedRwLock<A> as usize;
+    let b_addr = b as *const QueuedRwLock<B> as usize;
+    assert!(a_addr != b_addr, "write_both called with the same lock twice");
 
-    fn deref(&self) -> &T {
-        self.data
+    // The second ticket is only taken once the first lock is held, holding
+    // a place in its queue while waiting on the first would let a thread
+    // that's already past the first get stuck behind us
+    let (a_guard, b_guard) = if a_addr < b_addr {
+        let a_guard = a.write();
+        (a_guard, b.write())
+    } else {
+        let b_guard = b.write();
+        (a.write(), b_guard)
+    };
+
+    match (a_guard, b_guard) {
+        (Ok(a_guard), Ok(b_guard)) => Ok((a_guard, b_guard)),
+        (a_guard, b_guard) => {
+            let a_guard = a_guard.unwrap_or_else(PoisonError::into_inner);
+            let b_guard = b_guard.unwrap_or_else(PoisonError::into_inner);
+            Err(PoisonError::new((a_guard, b_guard)))
+        }
     }
 }
 
-impl<'a, T> Drop for QueuedRwLockReadGuard<'a, T> {
-    fn drop(&mut self) {
-        self.lock.inner.read_unlock()
+/// Write locks `locks[i]` for every `i` in `indices`, in ascending order
+/// of index whatever order they're passed in, so threads locking
+/// overlapping subsets of the same slice can't deadlock on each other. The
+/// guards come back in that order too.
+///
+/// Same as `write_both`, each ticket is only taken once the lock before it
+/// is held. If any lock is poisoned every guard comes back inside the error.
+///
+/// # Panics
+///
+/// Panics if an index is out of bounds or appears more than once.
+pub fn write_all<'a, T>(locks: &'a [QueuedRwLock<T>],
+                        indices: &[usize])
+                        -> LockResult<Vec<QueuedRwLockWriteGuard<'a, T>>> {
+    let mut indices = indices.to_vec();
+    indices.sort_unstable();
+    assert!(indices.windows(2).all(|pair| pair[0] != pair[1]),
+            "write_all called with the same index twice");
+
+    let mut poisoned = false;
+    let guards = indices.iter().map(|&i| {
+        locks[i].write().unwrap_or_else(|err| {
+            poisoned = true;
+            err.into_inner()
+        })
+    }).collect();
+
+    if poisoned {
+        Err(PoisonError::new(guards))
+    } else {
+        Ok(guards)
     }
 }
 
-#[must_use]
-pub struct QueuedRwLockWriteGuard<'a, T: 'a> {
-    lock: &'a QueuedRwLock<T>,
-    data: PoisonGuard<'a, T>,
+impl<T> PoisonInfo for QueuedRwLock<T> {
+    fn poison_cause(&self) -> Option<PoisonCause> {
+        self.data.cause()
+    }
 }
 
-impl<'a, T> QueuedRwLockWriteGuard<'a, T> {
-    unsafe fn new(ticket: QueuedRwLockTicketGuard<'a, T>)
-                  -> LockResult<QueuedRwLockWriteGuard<'a, T>> {
-        let result = poison::map_result((*ticket.lock.data.get()).lock(), |data| {
-            QueuedRwLockWriteGuard {
-                lock: ticket.lock,
-                data: data,
-            }
-        });
-
-        // Make sure we don't double lock
-        mem::forget(ticket);
-        result
+impl<T: Default> Default for QueuedRwLock<T> {
+    fn default() -> QueuedRwLock<T> {
+        QueuedRwLock::new(T::default())
     }
 }
 
-unsafe impl<'a, T: Send> Send for QueuedRwLockWriteGuard<'a, T> {}
-unsafe impl<'a, T: Sync> Sync for QueuedRwLockWriteGuard<'a, T> {}
+/// Read locks the source and clones the data into a new lock of its own.
+///
+/// The new lock is made the same as with `QueuedRwLock::new`. It doesn't
+/// keep the source's fairness, max_readers, spin_count, panic policy or
+/// observer.
+///
+/// A poisoned source is still cloned. The new lock starts out poisoned
+/// with the same `PoisonCause`, so the clone says just as much about the
+/// data as the original did.
+impl<T: Clone> Clone for QueuedRwLock<T> {
+    fn clone(&self) -> QueuedRwLock<T> {
+        let (data, cause) = match self.read() {
+            Ok(guard) => ((*guard).clone(), None),
+            Err(err) => ((**err.get_ref()).clone(), err.poison_cause()),
+        };
 
-impl<'a, T> Deref for QueuedRwLockWriteGuard<'a, T> {
-    type Target = T;
+        let lock = QueuedRwLock::new(data);
+        if let Some(cause) = cause {
+            lock.data.poison_with(cause);
+        }
+        lock
+    }
+}
 
-    fn deref(&self) -> &T {
-        self.data.get()
+impl<T> From<T> for QueuedRwLock<T> {
+    fn from(data: T) -> QueuedRwLock<T> {
+        QueuedRwLock::new(data)
     }
 }
 
-impl<'a, T> DerefMut for QueuedRwLockWriteGuard<'a, T> {
-    fn deref_mut(&mut self) -> &mut T {
-        self.data.get_mut()
+/// Serializes the data under a read lock, failing if the lock is poisoned.
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize> serde::Serialize for QueuedRwLock<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.read() {
+            Ok(guard) => guard.serialize(serializer),
+            Err(_) => Err(serde::ser::Error::custom("QueuedRwLock is poisoned")),
+        }
     }
 }
 
-impl<'a, T> Drop for QueuedRwLockWriteGuard<'a, T> {
-    fn drop(&mut self) {
-        self.lock.inner.write_unlock()
+#[cfg(feature = "serde")]
+impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for QueuedRwLock<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(QueuedRwLock::new)
     }
 }
 
 #[must_use]
-pub struct QueuedRwLockTicketGuard<'a, T: 'a> {
+pub struct QueuedRwLockReadGuard<'a, T: 'a> {
     lock: &'a QueuedRwLock<T>,
-    ticket: usize,
+    data: &'a T,
 }
 
-impl<'a, T> QueuedRwLockTicketGuard<'a, T> {
-    fn new(lock: &'a QueuedRwLock<T>, ticket: usize) -> QueuedRwLockTicketGuard<'a, T> {
-        QueuedRwLockTicketGuard {
-            lock: lock,
-            ticket: ticket,
-        }
+impl<'a, T> QueuedRwLockReadGuard<'a, T> {
+    unsafe fn new(lock: &'a QueuedRwLock<T>) -> LockResult<QueuedRwLockReadGuard<'a, T>> {
+        poison::map_result(lock.data.get(), |data| {
+            QueuedRwLockReadGuard { lock, data }
+        })
     }
 
-    pub fn write(self) -> LockResult<QueuedRwLockWriteGuard<'a, T>> {
-        self.lock.inner.write(self.ticket);
-        unsafe { QueuedRwLockWriteGuard::new(self) }
+    /// Makes a guard for part of the locked data, e.g. one field of a struct.
+    pub fn map<U: ?Sized, F>(this: Self, f: F) -> QueuedRwLockMappedReadGuard<'a, U>
+        where F: FnOnce(&T) -> &U
+    {
+        let raw = &this.lock.inner;
+        let data = f(this.data);
+
+        // Make sure we don't unlock early
+        mem::forget(this);
+
+        QueuedRwLockMappedReadGuard { raw, data }
+    }
+
+    /// Releases the read lock for a ticket taken while it was still held,
+    /// so no writer that comes along after this read can get in first.
+    ///
+    /// Writers already queued are still served first, the data may have
+    /// changed by the time the ticket gets its turn.
+    pub fn into_write_ticket(self) -> QueuedRwLockTicketGuard<'a, T> {
+        let ticket = self.lock.take_ticket();
+        drop(self);
+        ticket
+    }
+}
+
+unsafe impl<'a, T: Sync> Send for QueuedRwLockReadGuard<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for QueuedRwLockReadGuard<'a, T> {}
+
+impl<'a, T> Deref for QueuedRwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.data
+    }
+}
+
+impl<'a, T> AsRef<T> for QueuedRwLockReadGuard<'a, T> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for QueuedRwLockReadGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: fmt::Display> fmt::Display for QueuedRwLockReadGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<'a, T> PoisonInfo for QueuedRwLockReadGuard<'a, T> {
+    fn poison_cause(&self) -> Option<PoisonCause> {
+        self.lock.poison_cause()
+    }
+}
+
+impl<'a, T> Drop for QueuedRwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.inner.read_unlock()
+    }
+}
+
+#[must_use]
+pub struct QueuedRwLockMappedReadGuard<'a, T: ?Sized + 'a> {
+    raw: &'a RawQueuedRwLock,
+    data: &'a T,
+}
+
+impl<'a, T: ?Sized> QueuedRwLockMappedReadGuard<'a, T> {
+    pub fn map<U: ?Sized, F>(this: Self, f: F) -> QueuedRwLockMappedReadGuard<'a, U>
+        where F: FnOnce(&T) -> &U
+    {
+        let raw = this.raw;
+        let data = f(this.data);
+
+        // Make sure we don't unlock early
+        mem::forget(this);
+
+        QueuedRwLockMappedReadGuard { raw, data }
+    }
+}
+
+unsafe impl<'a, T: ?Sized + Sync> Send for QueuedRwLockMappedReadGuard<'a, T> {}
+unsafe impl<'a, T: ?Sized + Sync> Sync for QueuedRwLockMappedReadGuard<'a, T> {}
+
+impl<'a, T: ?Sized> Deref for QueuedRwLockMappedReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.data
+    }
+}
+
+impl<'a, T: ?Sized> AsRef<T> for QueuedRwLockMappedReadGuard<'a, T> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for QueuedRwLockMappedReadGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Display> fmt::Display for QueuedRwLockMappedReadGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized> Drop for QueuedRwLockMappedReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.raw.read_unlock()
+    }
+}
+
+#[must_use]
+pub struct QueuedRwLockUpgradableReadGuard<'a, T: 'a> {
+    lock: &'a QueuedRwLock<T>,
+    // Used up already, kept for the write lock this upgrades to
+    ticket: usize,
+    data: &'a T,
+}
+
+impl<'a, T> QueuedRwLockUpgradableReadGuard<'a, T> {
+    unsafe fn new(lock: &'a QueuedRwLock<T>, ticket: usize)
+                  -> LockResult<QueuedRwLockUpgradableReadGuard<'a, T>> {
+        poison::map_result(lock.data.get(), |data| {
+            QueuedRwLockUpgradableReadGuard { lock, ticket, data }
+        })
+    }
+
+    /// Waits for any other readers to finish and turns this into a write
+    /// guard. Writers queued behind this guard stay behind it.
+    pub fn upgrade(self) -> QueuedRwLockWriteGuard<'a, T> {
+        let lock = self.lock;
+        let ticket = self.ticket;
+
+        // Make sure we don't release the lock on the way
+        mem::forget(self);
+
+        lock.inner.upgrade();
+
+        let ticket = QueuedRwLockTicketGuard::new(lock, ticket);
+        unsafe { QueuedRwLockWriteGuard::new(ticket) }.unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Like `upgrade` but only if there are no other readers right now,
+    /// otherwise this guard is handed back.
+    pub fn try_upgrade(self) -> Result<QueuedRwLockWriteGuard<'a, T>, QueuedRwLockUpgradableReadGuard<'a, T>> {
+        if !self.lock.inner.try_upgrade() {
+            return Err(self);
+        }
+
+        let lock = self.lock;
+        let ticket = self.ticket;
+
+        // Make sure we don't release the lock on the way
+        mem::forget(self);
+
+        let ticket = QueuedRwLockTicketGuard::new(lock, ticket);
+        Ok(unsafe { QueuedRwLockWriteGuard::new(ticket) }.unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+unsafe impl<'a, T: Send + Sync> Send for QueuedRwLockUpgradableReadGuard<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for QueuedRwLockUpgradableReadGuard<'a, T> {}
+
+impl<'a, T> Deref for QueuedRwLockUpgradableReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.data
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for QueuedRwLockUpgradableReadGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: fmt::Display> fmt::Display for QueuedRwLockUpgradableReadGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<'a, T> PoisonInfo for QueuedRwLockUpgradableReadGuard<'a, T> {
+    fn poison_cause(&self) -> Option<PoisonCause> {
+        self.lock.poison_cause()
+    }
+}
+
+impl<'a, T> Drop for QueuedRwLockUpgradableReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.inner.upgradable_unlock()
+    }
+}
+
+#[must_use]
+pub struct QueuedRwLockWriteGuard<'a, T: 'a> {
+    lock: &'a QueuedRwLock<T>,
+    // Used up already, kept for downgrade_to_upgradable
+    ticket: usize,
+    // Dropped by hand so poisoning happens before the lock is released
+    data: ManuallyDrop<PoisonGuard<'a, T>>,
+}
+
+impl<'a, T> QueuedRwLockWriteGuard<'a, T> {
+    unsafe fn new(ticket: QueuedRwLockTicketGuard<'a, T>)
+                  -> LockResult<QueuedRwLockWriteGuard<'a, T>> {
+        let result = poison::map_result(ticket.lock.data.lock(), |data| {
+            QueuedRwLockWriteGuard {
+                lock: ticket.lock,
+                ticket: ticket.ticket,
+                data: ManuallyDrop::new(data),
+            }
+        });
+
+        // Make sure we don't double lock
+        mem::forget(ticket);
+        result
+    }
+
+    /// Sets what the `PoisonCause` will say if the thread panics while this
+    /// guard is held, e.g. what the guard is being used for.
+    pub fn set_poison_context<C: Into<String>>(&mut self, context: C) {
+        self.data.set_context(context.into());
+    }
+
+    /// Makes the lock poison when the guard is dropped unless `commit` is
+    /// called first, so an early return part way through an update counts
+    /// as a failure the same as a panic.
+    pub fn guarded(self) -> QueuedRwLockCommitGuard<'a, T> {
+        QueuedRwLockCommitGuard { guard: self, committed: false }
+    }
+
+    /// Stops a panic while this guard is held from poisoning the lock, e.g.
+    /// when the panic is caught and the data is known to be fine. The lock is
+    /// still released when the guard is dropped.
+    ///
+    /// A lock made with `OnPanic::Abort` still aborts, that was asked for up
+    /// front for every panic while a write is held.
+    pub fn disarm_poison(&mut self) {
+        self.data.disarm();
+    }
+
+    /// Releases the lock and waits for it again behind everyone that was
+    /// queued up, so a long run of writes doesn't keep them out the whole
+    /// time.
+    ///
+    /// Others see the data while the lock is released, it has to be in a
+    /// consistent state before calling this.
+    pub fn bump(&mut self) {
+        self.unlocked(|| ());
+    }
+
+    /// Releases the lock while `f` runs and waits for it again afterwards,
+    /// behind anyone that queued up in the meantime. `f` is free to use the
+    /// lock itself.
+    ///
+    /// **The data has to be in a consistent state before calling this**,
+    /// everyone else can see and change it while `f` runs. If `f` panics the
+    /// lock is taken back before unwinding carries on, and ends up poisoned.
+    pub fn unlocked<R, F>(&mut self, f: F) -> R
+        where F: FnOnce() -> R
+    {
+        self.lock.inner.write_unlock();
+        let _relock = Relock(&self.lock.inner);
+        f()
+    }
+
+    /// Turns this into a read guard without releasing the lock in between,
+    /// so no queued writer can get in first.
+    pub fn downgrade(mut self) -> QueuedRwLockReadGuard<'a, T> {
+        let lock = self.lock;
+        unsafe { ManuallyDrop::drop(&mut self.data) };
+
+        // Make sure we don't double unlock
+        mem::forget(self);
+
+        lock.inner.downgrade();
+        unsafe { QueuedRwLockReadGuard::new(lock) }.unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Has threads in `QueuedRwLock::wait_until` check again once this write
+    /// is done, see `QueuedRwLock::set_notify_on_write`.
+    pub fn notify_condition(&self) {
+        self.lock.inner.notify_condition();
+    }
+
+    /// Like `downgrade` but keeps the right to upgrade again, so writers
+    /// queued behind this guard stay behind it while readers are let back
+    /// in.
+    pub fn downgrade_to_upgradable(mut self) -> QueuedRwLockUpgradableReadGuard<'a, T> {
+        let lock = self.lock;
+        let ticket = self.ticket;
+        unsafe { ManuallyDrop::drop(&mut self.data) };
+
+        // Make sure we don't double unlock
+        mem::forget(self);
+
+        lock.inner.downgrade_to_upgradable(ticket);
+        unsafe { QueuedRwLockUpgradableReadGuard::new(lock, ticket) }.unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Makes a guard for part of the locked data, e.g. one field of a struct.
+    ///
+    /// Panicking while holding the new guard poisons the lock as usual.
+    pub fn map<U: ?Sized, F>(mut this: Self, f: F) -> QueuedRwLockMappedWriteGuard<'a, U>
+        where F: FnOnce(&mut T) -> &mut U
+    {
+        let raw = &this.lock.inner;
+
+        // Still holding the lock here so a panic in f unlocks on the way out
+        let data: *mut U = f(this.data.get_mut());
+        let poison = unsafe { ManuallyDrop::take(&mut this.data) };
+
+        // Make sure we don't unlock early
+        mem::forget(this);
+
+        QueuedRwLockMappedWriteGuard {
+            raw,
+            data: ManuallyDrop::new(unsafe { poison.map(data) }),
+        }
+    }
+}
+
+unsafe impl<'a, T: Send + Sync> Send for QueuedRwLockWriteGuard<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for QueuedRwLockWriteGuard<'a, T> {}
+
+impl<'a, T> Deref for QueuedRwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.data.get()
+    }
+}
+
+impl<'a, T> DerefMut for QueuedRwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<'a, T> AsRef<T> for QueuedRwLockWriteGuard<'a, T> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<'a, T> AsMut<T> for QueuedRwLockWriteGuard<'a, T> {
+    fn as_mut(&mut self) -> &mut T {
+        self
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for QueuedRwLockWriteGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: fmt::Display> fmt::Display for QueuedRwLockWriteGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<'a, T> PoisonInfo for QueuedRwLockWriteGuard<'a, T> {
+    fn poison_cause(&self) -> Option<PoisonCause> {
+        self.lock.poison_cause()
+    }
+}
+
+impl<'a, T> Drop for QueuedRwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.data) };
+        self.lock.inner.write_unlock()
+    }
+}
+
+// Takes the write lock back for unlocked, even when unwinding so the guard
+// has something to release
+struct Relock<'a>(&'a RawQueuedRwLock);
+
+impl<'a> Drop for Relock<'a> {
+    fn drop(&mut self) {
+        let ticket = self.0.take_ticket();
+        self.0.write(ticket);
+    }
+}
+
+#[must_use]
+pub struct QueuedRwLockMappedWriteGuard<'a, T: ?Sized + 'a> {
+    raw: &'a RawQueuedRwLock,
+    // Dropped by hand so poisoning happens before the lock is released
+    data: ManuallyDrop<PoisonGuard<'a, T>>,
+}
+
+impl<'a, T: ?Sized> QueuedRwLockMappedWriteGuard<'a, T> {
+    /// Same as `QueuedRwLockWriteGuard::set_poison_context`.
+    pub fn set_poison_context<C: Into<String>>(&mut self, context: C) {
+        self.data.set_context(context.into());
+    }
+
+    /// Same as `QueuedRwLockWriteGuard::disarm_poison`.
+    pub fn disarm_poison(&mut self) {
+        self.data.disarm();
+    }
+
+    pub fn map<U: ?Sized, F>(mut this: Self, f: F) -> QueuedRwLockMappedWriteGuard<'a, U>
+        where F: FnOnce(&mut T) -> &mut U
+    {
+        let raw = this.raw;
+
+        // Still holding the lock here so a panic in f unlocks on the way out
+        let data: *mut U = f(this.data.get_mut());
+        let poison = unsafe { ManuallyDrop::take(&mut this.data) };
+
+        // Make sure we don't unlock early
+        mem::forget(this);
+
+        QueuedRwLockMappedWriteGuard {
+            raw,
+            data: ManuallyDrop::new(unsafe { poison.map(data) }),
+        }
+    }
+}
+
+unsafe impl<'a, T: ?Sized + Send> Send for QueuedRwLockMappedWriteGuard<'a, T> {}
+unsafe impl<'a, T: ?Sized + Sync> Sync for QueuedRwLockMappedWriteGuard<'a, T> {}
+
+impl<'a, T: ?Sized> Deref for QueuedRwLockMappedWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.data.get()
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for QueuedRwLockMappedWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<'a, T: ?Sized> AsRef<T> for QueuedRwLockMappedWriteGuard<'a, T> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<'a, T: ?Sized> AsMut<T> for QueuedRwLockMappedWriteGuard<'a, T> {
+    fn as_mut(&mut self) -> &mut T {
+        self
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for QueuedRwLockMappedWriteGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Display> fmt::Display for QueuedRwLockMappedWriteGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized> Drop for QueuedRwLockMappedWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.data) };
+        self.raw.write_unlock()
+    }
+}
+
+/// A write guard from `QueuedRwLockWriteGuard::guarded`, it poisons the
+/// lock if it's dropped without calling `commit`.
+#[must_use]
+pub struct QueuedRwLockCommitGuard<'a, T: 'a> {
+    guard: QueuedRwLockWriteGuard<'a, T>,
+    committed: bool,
+}
+
+impl<'a, T> QueuedRwLockCommitGuard<'a, T> {
+    /// Releases the lock without poisoning it.
+    pub fn commit(mut self) {
+        self.committed = true;
+    }
+}
+
+impl<'a, T> Deref for QueuedRwLockCommitGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for QueuedRwLockCommitGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for QueuedRwLockCommitGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T> Drop for QueuedRwLockCommitGuard<'a, T> {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.guard.data.poison_on_drop();
+        }
+    }
+}
+
+/// A read guard from `QueuedRwLock::read_arc`.
+#[must_use]
+pub struct QueuedRwLockArcReadGuard<T: 'static> {
+    // Borrows from lock, so has to be dropped first
+    guard: QueuedRwLockReadGuard<'static, T>,
+    lock: Arc<QueuedRwLock<T>>,
+}
+
+impl<T> QueuedRwLockArcReadGuard<T> {
+    /// The lock this guard is for.
+    pub fn lock(this: &Self) -> &Arc<QueuedRwLock<T>> {
+        &this.lock
+    }
+}
+
+impl<T> Deref for QueuedRwLockArcReadGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for QueuedRwLockArcReadGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for QueuedRwLockArcReadGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<T> PoisonInfo for QueuedRwLockArcReadGuard<T> {
+    fn poison_cause(&self) -> Option<PoisonCause> {
+        self.lock.poison_cause()
+    }
+}
+
+/// A write guard from `QueuedRwLock::write_arc`.
+#[must_use]
+pub struct QueuedRwLockArcWriteGuard<T: 'static> {
+    // Borrows from lock, so has to be dropped first
+    guard: QueuedRwLockWriteGuard<'static, T>,
+    lock: Arc<QueuedRwLock<T>>,
+}
+
+impl<T> QueuedRwLockArcWriteGuard<T> {
+    /// The lock this guard is for.
+    pub fn lock(this: &Self) -> &Arc<QueuedRwLock<T>> {
+        &this.lock
+    }
+
+    /// Same as `QueuedRwLockWriteGuard::set_poison_context`.
+    pub fn set_poison_context<C: Into<String>>(&mut self, context: C) {
+        self.guard.set_poison_context(context);
+    }
+
+    /// Same as `QueuedRwLockWriteGuard::disarm_poison`.
+    pub fn disarm_poison(&mut self) {
+        self.guard.disarm_poison();
+    }
+}
+
+impl<T> Deref for QueuedRwLockArcWriteGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for QueuedRwLockArcWriteGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for QueuedRwLockArcWriteGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for QueuedRwLockArcWriteGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<T> PoisonInfo for QueuedRwLockArcWriteGuard<T> {
+    fn poison_cause(&self) -> Option<PoisonCause> {
+        self.lock.poison_cause()
+    }
+}
+
+#[must_use]
+pub struct QueuedRwLockTicketGuard<'a, T: 'a> {
+    lock: &'a QueuedRwLock<T>,
+    ticket: usize,
+}
+
+impl<'a, T> QueuedRwLockTicketGuard<'a, T> {
+    fn new(lock: &'a QueuedRwLock<T>, ticket: usize) -> QueuedRwLockTicketGuard<'a, T> {
+        QueuedRwLockTicketGuard { lock, ticket }
+    }
+
+    pub fn write(self) -> LockResult<QueuedRwLockWriteGuard<'a, T>> {
+        self.lock.inner.write(self.ticket);
+        unsafe { QueuedRwLockWriteGuard::new(self) }
+    }
+
+    /// Takes the write lock if it's this ticket's turn and no readers are
+    /// left, otherwise hands the ticket back with its place kept.
+    ///
+    /// The inner result reports poisoning the same as `write`.
+    pub fn try_write(self) -> Result<LockResult<QueuedRwLockWriteGuard<'a, T>>, QueuedRwLockTicketGuard<'a, T>> {
+        if self.lock.inner.try_write(self.ticket) {
+            Ok(unsafe { QueuedRwLockWriteGuard::new(self) })
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Waits for this ticket's turn but not for readers to leave, which is
+    /// left to the returned `QueuedRwLockPendingWrite`.
+    ///
+    /// New readers are kept out from here on, the same as during `write`.
+    pub fn write_dequeued(self) -> QueuedRwLockPendingWrite<'a, T> {
+        let lock = self.lock;
+        let ticket = self.ticket;
+        lock.inner.write_dequeue(ticket);
+
+        // The ticket is used up now
+        mem::forget(self);
+
+        QueuedRwLockPendingWrite { lock, ticket }
+    }
+
+    /// Number of writers that will be served before this ticket, 0 means
+    /// it's next in line.
+    pub fn position(&self) -> usize {
+        self.lock.inner.position(self.ticket)
+    }
+
+    /// A `Copy` handle for keeping an eye on this ticket's place in the
+    /// queue, it can't be used to take the lock.
+    pub fn handle(&self) -> QueuedRwLockTicketHandle<'a, T> {
+        QueuedRwLockTicketHandle {
+            lock: self.lock,
+            ticket: self.ticket,
+        }
+    }
+
+    /// Number of readers holding the lock, which `write` would have to wait
+    /// out once this ticket's turn comes. Same as `reader_count`, so an
+    /// upgradable reader is counted too.
+    pub fn readers_ahead(&self) -> usize {
+        self.lock.reader_count()
+    }
+
+    /// Gives up this place in the queue without taking the lock.
+    ///
+    /// Dropping a ticket has to wait its turn and briefly take the lock, this
+    /// returns straight away and never keeps readers out.
+    pub fn cancel(self) {
+        self.lock.inner.cancel_ticket(self.ticket);
+
+        // Make sure we don't retire the ticket twice
+        mem::forget(self);
+    }
+}
+
+// Whichever thread ends up with the ticket gets the write lock
+unsafe impl<'a, T: Send + Sync> Send for QueuedRwLockTicketGuard<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for QueuedRwLockTicketGuard<'a, T> {}
+
+impl<'a, T> fmt::Debug for QueuedRwLockTicketGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("QueuedRwLockTicketGuard")
+            .field("ticket", &self.ticket)
+            .field("position", &self.position())
+            .finish()
+    }
+}
+
+impl<'a, T> Drop for QueuedRwLockTicketGuard<'a, T> {
+    fn drop(&mut self) {
+        // This will only be called if we didn't take the lock, have to do so
+        // or we stall other users forever
+        #[cfg(feature = "debug_ticket_tracking")]
+        self.lock.inner.ticket_dropped(self.ticket);
+        self.lock.inner.write(self.ticket);
+        self.lock.inner.write_unlock();
+    }
+}
+
+/// A ticket's place in the queue, from `QueuedRwLockTicketGuard::handle`.
+///
+/// It only looks, the guard is still what writes or cancels. A handle can
+/// outlive its ticket being used up, from then on it's always current.
+pub struct QueuedRwLockTicketHandle<'a, T: 'a> {
+    lock: &'a QueuedRwLock<T>,
+    ticket: usize,
+}
+
+impl<'a, T> QueuedRwLockTicketHandle<'a, T> {
+    /// Same as `QueuedRwLockTicketGuard::position`.
+    pub fn position(&self) -> usize {
+        self.lock.inner.position(self.ticket)
+    }
+
+    /// Whether no other ticket is left to be served before this one.
+    pub fn is_current(&self) -> bool {
+        self.position() == 0
+    }
+}
+
+impl<'a, T> Clone for QueuedRwLockTicketHandle<'a, T> {
+    fn clone(&self) -> QueuedRwLockTicketHandle<'a, T> {
+        *self
+    }
+}
+
+impl<'a, T> Copy for QueuedRwLockTicketHandle<'a, T> {}
+
+impl<'a, T> fmt::Debug for QueuedRwLockTicketHandle<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("QueuedRwLockTicketHandle")
+            .field("ticket", &self.ticket)
+            .finish()
+    }
+}
+
+/// A write lock that's had its turn but is still waiting for readers to
+/// leave, from `QueuedRwLockTicketGuard::write_dequeued`.
+///
+/// Dropping it gives up the write and lets readers back in.
+#[must_use]
+pub struct QueuedRwLockPendingWrite<'a, T: 'a> {
+    lock: &'a QueuedRwLock<T>,
+    ticket: usize,
+}
+
+impl<'a, T> QueuedRwLockPendingWrite<'a, T> {
+    /// Waits for readers to leave and takes the write lock.
+    pub fn finish(self) -> LockResult<QueuedRwLockWriteGuard<'a, T>> {
+        self.lock.inner.write_drain(self.ticket);
+        unsafe { QueuedRwLockWriteGuard::new(self.into_ticket()) }
+    }
+
+    /// Like `finish` but gives up with `WouldBlock` if readers haven't left
+    /// once `dur` has elapsed, letting readers back in.
+    #[cfg(feature = "std")]
+    pub fn finish_timeout(self, dur: Duration) -> TryLockResult<QueuedRwLockWriteGuard<'a, T>> {
+        let deadline = match Instant::now().checked_add(dur) {
+            Some(deadline) => deadline,
+            None => return Ok(self.finish()?),
+        };
+
+        let lock = self.lock;
+        let ticket = self.ticket;
+
+        // Whichever way it goes there's nothing left to abandon
+        mem::forget(self);
+
+        if lock.inner.write_drain_until(ticket, deadline) {
+            let ticket = QueuedRwLockTicketGuard::new(lock, ticket);
+            Ok(unsafe { QueuedRwLockWriteGuard::new(ticket) }?)
+        } else {
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    /// Number of readers still to leave.
+    pub fn readers_left(&self) -> usize {
+        self.lock.reader_count()
+    }
+
+    fn into_ticket(self) -> QueuedRwLockTicketGuard<'a, T> {
+        let ticket = QueuedRwLockTicketGuard::new(self.lock, self.ticket);
+
+        // Make sure we don't abandon the write
+        mem::forget(self);
+        ticket
+    }
+}
+
+unsafe impl<'a, T: Send + Sync> Send for QueuedRwLockPendingWrite<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for QueuedRwLockPendingWrite<'a, T> {}
+
+impl<'a, T> fmt::Debug for QueuedRwLockPendingWrite<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("QueuedRwLockPendingWrite")
+            .field("ticket", &self.ticket)
+            .field("readers_left", &self.readers_left())
+            .finish()
+    }
+}
+
+impl<'a, T> Drop for QueuedRwLockPendingWrite<'a, T> {
+    fn drop(&mut self) {
+        self.lock.inner.write_abandon();
+    }
+}
+
+/// A place in the writer queue that only waits for its turn until a
+/// deadline, from `take_ticket_until`.
+///
+/// Dropping it without calling `write` waits for its turn the same as a
+/// `QueuedRwLockTicketGuard`, the deadline only applies to `write`.
+#[cfg(feature = "std")]
+#[must_use]
+pub struct QueuedRwLockTimedTicketGuard<'a, T: 'a> {
+    ticket: QueuedRwLockTicketGuard<'a, T>,
+    deadline: Instant,
+}
+
+#[cfg(feature = "std")]
+impl<'a, T> QueuedRwLockTimedTicketGuard<'a, T> {
+    /// Waits for this ticket's turn, giving up with `WouldBlock` once the
+    /// deadline has passed.
+    ///
+    /// A ticket that times out is given up the same as `cancel`, so writers
+    /// behind it are not held up.
+    pub fn write(self) -> TryLockResult<QueuedRwLockWriteGuard<'a, T>> {
+        let ticket = self.ticket;
+
+        if ticket.lock.inner.write_until(ticket.ticket, self.deadline) {
+            Ok(unsafe { QueuedRwLockWriteGuard::new(ticket) }?)
+        } else {
+            // Already retired by write_until
+            mem::forget(ticket);
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Number of writers that will be served before this ticket, 0 means
+    /// it's next in line.
+    pub fn position(&self) -> usize {
+        self.ticket.position()
+    }
+
+    /// Gives up this place in the queue without taking the lock.
+    pub fn cancel(self) {
+        self.ticket.cancel();
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, T> fmt::Debug for QueuedRwLockTimedTicketGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("QueuedRwLockTimedTicketGuard")
+            .field("ticket", &self.ticket.ticket)
+            .field("position", &self.position())
+            .field("deadline", &self.deadline)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn smoke() {
+        let lock = QueuedRwLock::new(());
+        drop(lock.read().unwrap());
+        drop(lock.write().unwrap());
+        drop((lock.read().unwrap(), lock.read().unwrap()));
+        drop(lock.write().unwrap());
+    }
+
+    #[test]
+    fn static_lock() {
+        static LOCK: QueuedRwLock<Vec<i32>> = QueuedRwLock::new(Vec::new());
+        LOCK.write().unwrap().push(1);
+        assert_eq!(*LOCK.read().unwrap(), [1]);
+    }
+
+    #[test]
+    fn try_read() {
+        let lock = QueuedRwLock::new(0);
+        let write_guard = lock.write().unwrap();
+
+        let read_result = lock.try_read();
+        match read_result {
+            Err(TryLockError::WouldBlock) => (),
+            Ok(_) => panic!("try_read should not succeed while write_guard is in scope"),
+            Err(_) => panic!("unexpected error"),
+        }
+
+        drop(write_guard);
+    }
+
+    #[test]
+    fn try_write() {
+        let lock = QueuedRwLock::new(0);
+        let read_guard = lock.read().unwrap();
+
+        let write_result = lock.try_write();
+        match write_result {
+            Err(TryLockError::WouldBlock) => (),
+            Ok(_) => panic!("try_write should not succeed while read_guard is in scope"),
+            Err(_) => panic!("unexpected error"),
+        }
+
+        drop(read_guard);
+    }
+
+    #[test]
+    fn try_write_never_jumps_queue() {
+        let lock = QueuedRwLock::new(0);
+        drop(lock.try_write().unwrap());
+
+        // Taken but not written, nobody holds the lock either time
+        let first = lock.take_ticket();
+        assert!(lock.try_write().is_err());
+        let second = lock.take_ticket();
+        assert!(lock.try_write().is_err());
+
+        // Still blocked by the first with the second given up
+        second.cancel();
+        assert!(lock.try_write().is_err());
+        first.cancel();
+        drop(lock.try_write().unwrap());
+    }
+
+    #[test]
+    fn wait_until() {
+        let lock = QueuedRwLock::new(0);
+
+        thread::scope(|s| {
+            let waiter = s.spawn(|| *lock.wait_until(|&n| n >= 3).unwrap());
+            for _ in 0..5 {
+                thread::sleep(Duration::from_millis(1));
+                *lock.write().unwrap() += 1;
+            }
+            assert!(waiter.join().unwrap() >= 3);
+        });
+
+        // Already true, doesn't wait
+        assert_eq!(*lock.wait_until(|&n| n == 5).unwrap(), 5);
+    }
+
+    #[test]
+    fn notify_condition() {
+        let lock = QueuedRwLock::new(0);
+        lock.set_notify_on_write(false);
+
+        thread::scope(|s| {
+            let waiter = s.spawn(|| *lock.wait_until(|&n| n >= 4).unwrap());
+
+            // Whenever the waiter first looks it has to wait for a notify
+            for _ in 0..3 {
+                *lock.write().unwrap() += 1;
+            }
+            thread::sleep(Duration::from_millis(10));
+            assert!(!waiter.is_finished());
+
+            let mut guard = lock.write().unwrap();
+            *guard += 1;
+            guard.notify_condition();
+            drop(guard);
+            assert_eq!(waiter.join().unwrap(), 4);
+        });
+    }
+
+    #[test]
+    fn write_would_block() {
+        let lock = QueuedRwLock::new(0);
+        assert!(!lock.write_would_block());
+
+        let read_guard = lock.read().unwrap();
+        assert!(lock.write_would_block());
+        drop(read_guard);
+
+        let ticket = lock.take_ticket();
+        assert!(lock.write_would_block());
+        let write_guard = ticket.write().unwrap();
+        assert!(lock.write_would_block());
+        drop(write_guard);
+
+        assert!(!lock.write_would_block());
+        assert_eq!(lock.write_counts().requested, 1);
+    }
+
+    #[test]
+    fn write_priority() {
+        let lock = QueuedRwLock::new(Vec::new());
+        let guard = lock.write().unwrap();
+
+        thread::scope(|s| {
+            for i in 0..3 {
+                let lock = &lock;
+                s.spawn(move || lock.write().unwrap().push(i));
+                while lock.queue_len() != i + 1 {
+                    thread::yield_now();
+                }
+            }
+
+            s.spawn(|| lock.write_priority().unwrap().push(10));
+            while lock.queue_len() != 4 {
+                thread::yield_now();
+            }
+            assert!(lock.try_write().is_err());
+            drop(guard);
+        });
+
+        assert_eq!(*lock.read().unwrap(), [10, 0, 1, 2]);
+    }
+
+    #[test]
+    fn try_read_reason() {
+        let lock = QueuedRwLock::with_max_readers(0, 1);
+
+        let read_guard = lock.read().unwrap();
+        assert!(matches!(lock.try_read_reason(), Err(TryReadError::MaxReaders)));
+        let ticket = lock.take_ticket();
+        assert!(matches!(lock.try_read_reason(), Err(TryReadError::WriterQueued)));
+        drop(read_guard);
+
+        let write_guard = ticket.write().unwrap();
+        assert!(matches!(lock.try_read_reason(), Err(TryReadError::WriterActive)));
+        drop(write_guard);
+
+        assert!(lock.try_read_reason().is_ok());
+    }
+
+    #[test]
+    fn try_write_reason() {
+        let lock = QueuedRwLock::new(0);
+
+        let read_guard = lock.read().unwrap();
+        assert!(matches!(lock.try_write_reason(), Err(TryWriteError::ReadersActive)));
+        let ticket = lock.take_ticket();
+        assert!(matches!(lock.try_write_reason(), Err(TryWriteError::QueueNonEmpty)));
+        drop(read_guard);
+
+        let write_guard = ticket.write().unwrap();
+        assert!(matches!(lock.try_write_reason(), Err(TryWriteError::WriterActive)));
+        drop(write_guard);
+
+        *lock.try_write_reason().unwrap() += 1;
+        lock.poison();
+        assert!(matches!(lock.try_write_reason(), Err(TryWriteError::Poisoned(_))));
+    }
+
+    #[test]
+    fn wait_idle() {
+        let lock = QueuedRwLock::new(0);
+        lock.wait_idle();
+
+        let read_guard = lock.read().unwrap();
+        let ticket = lock.take_ticket();
+        let done = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                lock.wait_idle();
+                done.store(true, Ordering::SeqCst);
+            });
+
+            thread::sleep(Duration::from_millis(10));
+            drop(read_guard);
+            thread::sleep(Duration::from_millis(10));
+            assert!(!done.load(Ordering::SeqCst));
+
+            *ticket.write().unwrap() += 1;
+        });
+
+        assert!(done.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn borrow() {
+        let lock = QueuedRwLock::new(0);
+        *lock.borrow_mut() += 1;
+        assert_eq!(*lock.borrow(), 1);
+        let _guards = (lock.borrow(), lock.borrow());
+    }
+
+    #[test]
+    #[should_panic(expected = "QueuedRwLock already locked, can't borrow for writing")]
+    fn borrow_mut_while_borrowed() {
+        let lock = QueuedRwLock::new(0);
+        let _guard = lock.borrow();
+        let _guard = lock.borrow_mut();
+    }
+
+    #[test]
+    #[should_panic(expected = "QueuedRwLock already locked, can't borrow for reading")]
+    fn borrow_while_borrowed_mut() {
+        let lock = QueuedRwLock::new(0);
+        let _guard = lock.borrow_mut();
+        let _guard = lock.borrow();
+    }
+
+    #[test]
+    fn std_names() {
+        fn bump(lock: &RwLock<i32>) -> TryLockResult<RwLockWriteGuard<'_, i32>> {
+            let mut guard = lock.try_write()?;
+            *guard += 1;
+            Ok(guard)
+        }
+
+        let mut lock: RwLock<i32> = RwLock::new(0);
+        drop(bump(&lock).unwrap());
+        {
+            let guard: RwLockReadGuard<'_, i32> = lock.read().unwrap();
+            assert!(lock.try_read().is_ok());
+            assert_eq!(*guard, 1);
+        }
+        *lock.write().unwrap() += 1;
+        *lock.get_mut().unwrap() += 1;
+        assert_eq!(lock.into_inner().unwrap(), 3);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn take_ticket_until() {
+        let lock = QueuedRwLock::new(0);
+        let read_guard = lock.read().unwrap();
+
+        let timed = lock.take_ticket_until(Instant::now() + Duration::from_millis(10));
+        let next = lock.take_ticket();
+        assert_eq!(timed.position(), 0);
+
+        match timed.write() {
+            Err(TryLockError::WouldBlock) => (),
+            Ok(_) => panic!("timed ticket should not get the lock while read_guard is in scope"),
+            Err(_) => panic!("unexpected error"),
+        }
+
+        // The timed out ticket doesn't hold up the one behind it
+        assert_eq!(next.position(), 0);
+        drop(read_guard);
+        *next.write().unwrap() += 1;
+
+        let timed = lock.take_ticket_until(Instant::now() + Duration::from_secs(5));
+        *timed.write().unwrap() += 1;
+        assert_eq!(*lock.read().unwrap(), 2);
+    }
+
+    #[cfg(feature = "debug_ticket_tracking")]
+    #[test]
+    fn ticket_dropped() {
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct Dropped(Mutex<Vec<usize>>);
+
+        impl LockObserver for Dropped {
+            fn ticket_dropped(&self, ticket: usize) {
+                self.0.lock().unwrap().push(ticket);
+            }
+        }
+
+        let dropped = Arc::new(Dropped::default());
+        let lock = QueuedRwLock::with_observer(0, dropped.clone());
+
+        drop(lock.take_ticket().write().unwrap());
+        lock.take_ticket().cancel();
+        drop(lock.take_ticket());
+        drop(lock.write().unwrap());
+
+        assert_eq!(*dropped.0.lock().unwrap(), [2]);
+    }
+
+    #[test]
+    fn peek() {
+        let lock = QueuedRwLock::new(1);
+        assert_eq!(lock.peek(|x| x + 1), Some(2));
+        assert_eq!(lock.reader_count(), 0);
+
+        let write_guard = lock.write().unwrap();
+        assert_eq!(lock.peek(|x| x + 1), None);
+        drop(write_guard);
+    }
+
+    #[test]
+    fn observer() {
+        use std::sync::Mutex;
+        use std::time::Duration;
+
+        #[derive(Default)]
+        struct Events(Mutex<Vec<String>>);
+
+        impl LockObserver for Events {
+            fn read_acquired(&self, _wait: Duration) {
+                self.0.lock().unwrap().push("read".to_string());
+            }
+
+            fn read_released(&self) {
+                self.0.lock().unwrap().push("read_unlock".to_string());
+            }
+
+            fn write_acquired(&self, ticket: usize, _wait: Duration) {
+                self.0.lock().unwrap().push(format!("write {}", ticket));
+            }
+
+            fn write_released(&self) {
+                self.0.lock().unwrap().push("write_unlock".to_string());
+            }
+
+            fn ticket_taken(&self, ticket: usize) {
+                self.0.lock().unwrap().push(format!("ticket {}", ticket));
+            }
+        }
+
+        let events = Arc::new(Events::default());
+        let lock = QueuedRwLock::with_observer(0, events.clone());
+
+        drop(lock.read().unwrap());
+        let guard = lock.write().unwrap();
+        assert!(lock.try_read().is_err());
+        drop(guard.downgrade());
+        drop(lock.try_write().unwrap());
+        drop(lock.upgradable_read().unwrap().upgrade());
+
+        assert_eq!(*events.0.lock().unwrap(), [
+            "read", "read_unlock",
+            "ticket 0", "write 0", "write_unlock", "read", "read_unlock",
+            "ticket 1", "write 1", "write_unlock",
+            "ticket 2", "read", "read_unlock", "write 2", "write_unlock",
+        ]);
+    }
+
+    #[test]
+    fn write_dequeued() {
+        let lock = QueuedRwLock::new(0);
+        let read_guard = lock.read().unwrap();
+
+        let pending = lock.take_ticket().write_dequeued();
+        assert_eq!(pending.readers_left(), 1);
+        assert!(lock.try_read().is_err());
+
+        // Giving up lets readers back in
+        drop(pending);
+        drop(lock.try_read().unwrap());
+
+        let pending = lock.take_ticket().write_dequeued();
+        drop(read_guard);
+        *pending.finish().unwrap() += 1;
+        assert_eq!(*lock.read().unwrap(), 1);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn pending_write_finish_timeout() {
+        let lock = QueuedRwLock::new(0);
+        let read_guard = lock.read().unwrap();
+
+        let pending = lock.take_ticket().write_dequeued();
+        let next = lock.take_ticket();
+        match pending.finish_timeout(Duration::from_millis(10)) {
+            Err(TryLockError::WouldBlock) => (),
+            Ok(_) => panic!("finish_timeout should not succeed while read_guard is in scope"),
+            Err(_) => panic!("unexpected error"),
+        }
+
+        // Not left half locked, the next writer only waits on the reader
+        assert!(!lock.is_write_locked());
+        assert_eq!(next.position(), 0);
+        drop(read_guard);
+
+        *next.write().unwrap() += 1;
+        let pending = lock.take_ticket().write_dequeued();
+        *pending.finish_timeout(Duration::from_millis(10)).unwrap() += 1;
+        assert_eq!(*lock.read().unwrap(), 2);
+    }
+
+    #[test]
+    fn commit_guard() {
+        fn update(lock: &QueuedRwLock<i32>) -> Result<(), ()> {
+            let mut guard = lock.write().unwrap().guarded();
+            *guard += 1;
+            if *guard > 1 {
+                return Err(());
+            }
+            guard.commit();
+            Ok(())
+        }
+
+        let lock = QueuedRwLock::new(0);
+        assert_eq!(update(&lock), Ok(()));
+        assert!(!lock.is_poisoned());
+
+        assert_eq!(update(&lock), Err(()));
+        assert!(lock.is_poisoned());
+        assert!(!lock.poison_cause().unwrap().panicked());
+        assert_eq!(*lock.read().unwrap_err().into_inner(), 2);
+    }
+
+    // Runs itself again in a child process to do the panicking, since the
+    // abort would take the whole test run down with it
+    #[cfg(all(feature = "std", unix))]
+    #[test]
+    fn panic_policy_abort() {
+        use std::env;
+        use std::os::unix::process::ExitStatusExt;
+        use std::process::{Command, Stdio};
+
+        if env::var_os("QUEUEDRWLOCK_ABORT_CHILD").is_some() {
+            let lock = QueuedRwLock::with_panic_policy(0, OnPanic::Abort);
+            let _guard = lock.write().unwrap();
+            panic!("abort expected");
+        }
+
+        let status = Command::new(env::current_exe().unwrap())
+            .args(["--exact", "tests::panic_policy_abort", "--test-threads=1"])
+            .env("QUEUEDRWLOCK_ABORT_CHILD", "1")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert_eq!(status.signal(), Some(6));
+
+        // Reads and panics that aren't under a write lock don't abort
+        let lock = QueuedRwLock::with_panic_policy(0, OnPanic::Abort);
+        let _ = thread::scope(|s| {
+            s.spawn(|| {
+                let _guard = lock.read().unwrap();
+                panic!();
+            }).join()
+        });
+        assert!(!lock.is_poisoned());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn disarm_poison() {
+        let lock = QueuedRwLock::new(0);
+
+        let _ = thread::scope(|s| {
+            s.spawn(|| {
+                let mut guard = lock.write().unwrap();
+                guard.disarm_poison();
+                *guard += 1;
+                panic!();
+            }).join()
+        });
+
+        assert!(!lock.is_poisoned());
+        assert_eq!(*lock.read().unwrap(), 1);
+        assert_eq!(*lock.write().unwrap(), 1);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn poison_cause() {
+        let lock = QueuedRwLock::new(0);
+        assert_eq!(lock.poison_cause(), None);
+
+        let _ = thread::scope(|s| {
+            s.spawn(|| {
+                let mut guard = lock.write().unwrap();
+                guard.set_poison_context("bumping");
+                *guard += 1;
+                panic!();
+            }).join()
+        });
+
+        match lock.write() {
+            Err(err) => {
+                let cause = err.poison_cause().unwrap();
+                assert_eq!(cause.context(), Some("bumping"));
+                assert!(cause.panicked());
+            }
+            Ok(_) => panic!("lock should be poisoned"),
+        }
+        match lock.try_read() {
+            Err(err) => assert_eq!(err.poison_cause().unwrap().context(), Some("bumping")),
+            Ok(_) => panic!("lock should be poisoned"),
+        }
+
+        lock.clear_poison();
+        assert_eq!(lock.poison_cause(), None);
+
+        // Without a context there's still a cause
+        let _ = thread::scope(|s| {
+            s.spawn(|| {
+                let _guard = lock.write().unwrap();
+                panic!();
+            }).join()
+        });
+        assert_eq!(lock.poison_cause().unwrap().context(), None);
+    }
+
+    #[test]
+    fn poison() {
+        let lock = QueuedRwLock::new(0);
+        assert!(lock.poison_error().is_none());
+        lock.poison();
+        assert!(lock.poison_error().is_some());
+
+        match lock.try_read() {
+            Err(TryLockError::Poisoned(err)) => assert!(!err.poison_cause().unwrap().panicked()),
+            _ => panic!("try_read should see the poison"),
+        }
+
+        let guard = lock.write().unwrap_err().into_inner();
+        lock.clear_poison();
+        lock.poison();
+        drop(guard);
+        assert!(lock.read().is_err());
+
+        lock.clear_poison();
+        assert!(lock.read().is_ok());
+    }
+
+    #[test]
+    fn write_both() {
+        let a = QueuedRwLock::new(0);
+        let b = QueuedRwLock::new(String::new());
+
+        thread::scope(|s| {
+            for i in 0..4 {
+                let (a, b) = (&a, &b);
+                s.spawn(move || {
+                    for _ in 0..100 {
+                        // Half the threads pass them the other way round
+                        let (mut x, mut y) = if i % 2 == 0 {
+                            super::write_both(a, b).unwrap()
+                        } else {
+                            let (y, x) = super::write_both(b, a).unwrap();
+                            (x, y)
+                        };
+                        *x += 1;
+                        y.push('x');
+                    }
+                });
+            }
+        });
+
+        assert_eq!(*a.read().unwrap(), 400);
+        assert_eq!(b.read().unwrap().len(), 400);
+
+        a.poison();
+        let (a_guard, b_guard) = super::write_both(&a, &b).unwrap_err().into_inner();
+        assert_eq!((*a_guard, b_guard.len()), (400, 400));
+    }
+
+    #[test]
+    fn write_all() {
+        let locks: Vec<_> = (0..4).map(|_| QueuedRwLock::new(0)).collect();
+
+        thread::scope(|s| {
+            for i in 0..4 {
+                let locks = &locks;
+                s.spawn(move || {
+                    let indices = [i, (i + 1) % 4, (i + 2) % 4];
+                    for _ in 0..100 {
+                        for mut guard in super::write_all(locks, &indices).unwrap() {
+                            *guard += 1;
+                        }
+                    }
+                });
+            }
+        });
+
+        let counts: Vec<_> = locks.iter().map(|lock| *lock.read().unwrap()).collect();
+        assert_eq!(counts, [300, 300, 300, 300]);
+
+        locks[2].poison();
+        let guards = super::write_all(&locks, &[3, 1]).unwrap();
+        assert_eq!(guards.len(), 2);
+        drop(guards);
+        let guards = super::write_all(&locks, &[2, 0]).unwrap_err().into_inner();
+        assert_eq!(guards.len(), 2);
+    }
+
+    #[test]
+    fn generation() {
+        let lock = QueuedRwLock::new(0);
+        let start = lock.generation();
+
+        drop(lock.read().unwrap());
+        assert_eq!(lock.generation(), start);
+
+        *lock.write().unwrap() += 1;
+        let after_write = lock.generation();
+        assert!(after_write != start);
+
+        drop(lock.try_write().unwrap().downgrade());
+        assert!(lock.generation() != after_write);
+    }
+
+    #[test]
+    fn write_counts() {
+        let lock = QueuedRwLock::new(0);
+        assert_eq!(lock.write_counts(), WriteCounts { requested: 0, completed: 0 });
+
+        *lock.write().unwrap() += 1;
+        let ticket = lock.take_ticket();
+        assert_eq!(lock.write_counts(), WriteCounts { requested: 2, completed: 1 });
+
+        let guard = ticket.write().unwrap();
+        assert_eq!(lock.write_counts().completed, 1);
+        drop(guard);
+        assert_eq!(lock.write_counts(), WriteCounts { requested: 2, completed: 2 });
+
+        // Priority writes don't take a ticket but still count as requested
+        *lock.write_priority().unwrap() += 1;
+        assert_eq!(lock.write_counts(), WriteCounts { requested: 3, completed: 3 });
+
+        // Cancelled tickets and upgradable reads count as requested but never
+        // complete unless upgraded
+        lock.take_ticket().cancel();
+        drop(lock.upgradable_read().unwrap());
+        assert_eq!(lock.write_counts(), WriteCounts { requested: 5, completed: 3 });
+        drop(lock.upgradable_read().unwrap().upgrade());
+        assert_eq!(lock.write_counts(), WriteCounts { requested: 6, completed: 4 });
+    }
+
+    #[test]
+    fn optimistic_read() {
+        let lock = QueuedRwLock::new((0u64, 0u64));
+        assert_eq!(lock.optimistic_read(), Some((0, 0)));
+
+        let write_guard = lock.write().unwrap();
+        assert_eq!(lock.optimistic_read(), None);
+        drop(write_guard);
+
+        let stop = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..1000 {
+                    *lock.write().unwrap() = (i, i);
+                }
+                stop.store(true, Ordering::SeqCst);
+            });
+
+            while !stop.load(Ordering::SeqCst) {
+                if let Some((a, b)) = lock.optimistic_read() {
+                    assert_eq!(a, b, "torn read");
+                }
+            }
+        });
+
+        assert_eq!(lock.optimistic_read(), Some((999, 999)));
+    }
+
+    #[test]
+    fn ignore_poison() {
+        let lock = QueuedRwLock::new(0);
+        lock.poison();
+
+        *lock.write_ignore_poison() += 1;
+        assert_eq!(*lock.read_ignore_poison(), 1);
+        assert!(lock.is_poisoned());
+    }
+
+    #[test]
+    fn try_write_fresh_lock() {
+        let lock = QueuedRwLock::new(0);
+        *lock.try_write().unwrap() = 1;
+        *lock.try_write().unwrap() = 2;
+        assert_eq!(*lock.read().unwrap(), 2);
+    }
+
+    #[test]
+    fn max_readers() {
+        let lock = QueuedRwLock::with_max_readers(0, 2);
+        let first = lock.read().unwrap();
+        let second = lock.read().unwrap();
+
+        match lock.try_read() {
+            Err(TryLockError::WouldBlock) => (),
+            Ok(_) => panic!("try_read should not succeed at capacity"),
+            Err(_) => panic!("unexpected error"),
+        }
+
+        thread::scope(|s| {
+            let reader = s.spawn(|| *lock.read().unwrap());
+            thread::sleep(Duration::from_millis(10));
+            assert_eq!(lock.reader_count(), 2);
+
+            drop(first);
+            assert_eq!(reader.join().unwrap(), 0);
+        });
+
+        drop(second);
+        drop(lock.try_write().unwrap());
+    }
+
+    #[test]
+    fn static_max_readers() {
+        static LOCK: QueuedRwLock<i32> = QueuedRwLock::with_max_readers(0, 1);
+        let guard = LOCK.read().unwrap();
+        assert!(LOCK.try_read().is_err());
+        drop(guard);
+        drop(LOCK.try_read().unwrap());
+    }
+
+    #[test]
+    fn max_readers_with_queued_writer() {
+        use std::sync::mpsc;
+
+        let lock = QueuedRwLock::with_max_readers(0, 1);
+        let read_guard = lock.read().unwrap();
+        let (tx, rx) = mpsc::channel();
+
+        thread::scope(|s| {
+            // Waits for the place, then the writer waits for it
+            s.spawn(|| {
+                let _guard = lock.read().unwrap();
+                tx.send("read").unwrap();
+            });
+            thread::sleep(Duration::from_millis(10));
+            s.spawn(|| {
+                let _guard = lock.write().unwrap();
+                tx.send("write").unwrap();
+            });
+            while lock.queue_len() == 0 {
+                thread::yield_now();
+            }
+
+            drop(read_guard);
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("read"));
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("write"));
+        });
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn read_yield() {
+        let lock = QueuedRwLock::new(0);
+        let write_guard = lock.write().unwrap();
+
+        match lock.read_yield(10) {
+            Err(TryLockError::WouldBlock) => (),
+            Ok(_) => panic!("read_yield should not succeed while write_guard is in scope"),
+            Err(_) => panic!("unexpected error"),
+        }
+
+        thread::scope(|s| {
+            let reader = s.spawn(|| *lock.read_yield(usize::MAX).unwrap());
+            thread::sleep(Duration::from_millis(10));
+            drop(write_guard);
+            assert_eq!(reader.join().unwrap(), 0);
+        });
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn read_timeout() {
+        let lock = QueuedRwLock::new(0);
+        let write_guard = lock.write().unwrap();
+
+        match lock.read_timeout(Duration::from_millis(10)) {
+            Err(TryLockError::WouldBlock) => (),
+            Ok(_) => panic!("read_timeout should not succeed while write_guard is in scope"),
+            Err(_) => panic!("unexpected error"),
+        }
+
+        drop(write_guard);
+        drop(lock.read_timeout(Duration::from_millis(10)).unwrap());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn write_timeout() {
+        let lock = QueuedRwLock::new(0);
+        let read_guard = lock.read().unwrap();
+
+        match lock.write_timeout(Duration::from_millis(10)) {
+            Err(TryLockError::WouldBlock) => (),
+            Ok(_) => panic!("write_timeout should not succeed while read_guard is in scope"),
+            Err(_) => panic!("unexpected error"),
+        }
+
+        drop(read_guard);
+        drop(lock.write_timeout(Duration::from_millis(10)).unwrap());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn write_drain_timeout() {
+        let lock = QueuedRwLock::new(0);
+        let read_guard = lock.read().unwrap();
+        let ticket = lock.take_ticket();
+
+        thread::scope(|s| {
+            let writer = s.spawn(|| lock.write_drain_timeout(Duration::from_millis(1)).map(|_| ()));
+
+            // Waiting in the queue isn't timed
+            thread::sleep(Duration::from_millis(20));
+            assert!(!writer.is_finished());
+
+            ticket.cancel();
+            match writer.join().unwrap() {
+                Err(TryLockError::WouldBlock) => (),
+                _ => panic!("write_drain_timeout should time out while read_guard is in scope"),
+            }
+        });
+
+        assert_eq!(lock.queue_len(), 0);
+        drop(lock.try_read().unwrap());
+        drop(read_guard);
+        *lock.write_drain_timeout(Duration::from_millis(10)).unwrap() += 1;
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn lock_until() {
+        use std::time::Instant;
+
+        let lock = QueuedRwLock::new(0);
+        let past = Instant::now();
+
+        let read_guard = lock.try_read_until(past).unwrap();
+        assert!(lock.try_write_until(past).is_err());
+        assert!(lock.try_write_until(Instant::now() + Duration::from_millis(10)).is_err());
+        assert_eq!(lock.queue_len(), 0);
+
+        drop(read_guard);
+        let write_guard = lock.try_write_until(past).unwrap();
+        assert!(lock.try_read_until(Instant::now() + Duration::from_millis(10)).is_err());
+        drop(write_guard);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn write_timeout_retires_ticket() {
+        let lock = QueuedRwLock::new(0);
+        let write_guard = lock.write().unwrap();
+        let ticket = lock.take_ticket();
+
+        // Times out behind an outstanding ticket, so has to be skipped later
+        assert!(lock.write_timeout(Duration::from_millis(10)).is_err());
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                *ticket.write().unwrap() += 1;
+                *lock.write().unwrap() += 1;
+            });
+
+            drop(write_guard);
+        });
+
+        assert_eq!(*lock.read().unwrap(), 2);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn is_poisoned() {
+        let lock = QueuedRwLock::new(0);
+        assert!(!lock.is_poisoned());
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                let _guard = lock.write().unwrap();
+                panic!("poison the lock");
+            }).join().unwrap_err();
+        });
+
+        // Doesn't need the lock, so works even while it's held
+        let write_result = lock.write();
+        assert!(lock.is_poisoned());
+        assert!(write_result.is_err());
+    }
+
+    #[test]
+    fn downgrade() {
+        let lock = QueuedRwLock::new(0);
+        let mut write_guard = lock.write().unwrap();
+        *write_guard = 1;
+
+        let read_guard = write_guard.downgrade();
+        assert_eq!(*read_guard, 1);
+        assert!(lock.try_write().is_err());
+        drop(lock.try_read().unwrap());
+
+        drop(read_guard);
+        *lock.write().unwrap() = 2;
+    }
+
+    #[test]
+    fn downgrade_to_upgradable() {
+        let lock = QueuedRwLock::new(0);
+        let mut write_guard = lock.write().unwrap();
+        *write_guard = 1;
+
+        let upgradable = write_guard.downgrade_to_upgradable();
+        assert_eq!(*upgradable, 1);
+        assert_eq!(*lock.try_read().unwrap(), 1);
+        assert!(lock.try_write().is_err());
+
+        thread::scope(|s| {
+            let writer = s.spawn(|| *lock.write().unwrap() += 10);
+            while lock.queue_len() == 0 {
+                thread::yield_now();
+            }
+
+            // Goes back to writing ahead of the queued writer
+            let mut write_guard = upgradable.upgrade();
+            *write_guard += 1;
+            drop(write_guard.downgrade_to_upgradable());
+            writer.join().unwrap();
+        });
+
+        assert_eq!(*lock.read().unwrap(), 12);
+    }
+
+    #[test]
+    fn downgrade_to_upgradable_keeps_ticket() {
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct Tickets(Mutex<Vec<usize>>);
+
+        impl LockObserver for Tickets {
+            fn write_acquired(&self, ticket: usize, _wait: Duration) {
+                self.0.lock().unwrap().push(ticket);
+            }
+        }
+
+        let tickets = Arc::new(Tickets::default());
+        let lock = QueuedRwLock::with_observer(0, tickets.clone());
+
+        // A ticket given up while the write is held moves the queue on
+        let write_guard = lock.write().unwrap();
+        lock.take_ticket().cancel();
+        drop(write_guard.downgrade_to_upgradable().upgrade());
+
+        // A priority write has no ticket of its own
+        drop(lock.write_priority().unwrap().downgrade_to_upgradable().upgrade());
+
+        assert_eq!(*tickets.0.lock().unwrap(), [0, 0, 2, 2]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn write_timeout_leaves_queue_usable() {
+        let lock = QueuedRwLock::new(0);
+        let read_guard = lock.read().unwrap();
+
+        // Gives up while waiting on the reader, which mustn't keep other
+        // readers out afterwards
+        assert!(lock.write_timeout(Duration::from_millis(1)).is_err());
+        drop(lock.try_read().unwrap());
+
+        thread::scope(|s| {
+            let mut handles = Vec::new();
+            for i in 0..8 {
+                let lock = &lock;
+                handles.push(s.spawn(move || {
+                    let timeout = Duration::from_millis(if i % 2 == 0 { 1 } else { 1000 });
+                    if let Ok(mut guard) = lock.write_timeout(timeout) {
+                        *guard += 1;
+                    }
+                }));
+            }
+
+            thread::sleep(Duration::from_millis(20));
+            drop(read_guard);
+
+            for handle in handles {
+                handle.join().unwrap();
+            }
+        });
+
+        assert_eq!(lock.queue_len(), 0);
+        *lock.write().unwrap() += 1;
+        assert!(*lock.read().unwrap() >= 5);
+    }
+
+    #[test]
+    fn upgradable_read() {
+        let lock = QueuedRwLock::new(0);
+        let upgradable = lock.upgradable_read().unwrap();
+
+        // Plain readers can share with the upgradable reader but writers can't
+        drop(lock.try_read().unwrap());
+        assert!(lock.try_write().is_err());
+
+        let mut write_guard = upgradable.upgrade();
+        *write_guard = 1;
+        drop(write_guard);
+
+        drop(lock.upgradable_read().unwrap());
+        assert_eq!(*lock.write().unwrap(), 1);
+    }
+
+    #[test]
+    fn try_upgrade() {
+        let lock = QueuedRwLock::new(0);
+        let upgradable = lock.upgradable_read().unwrap();
+        let read_guard = lock.read().unwrap();
+
+        let upgradable = match upgradable.try_upgrade() {
+            Err(upgradable) => upgradable,
+            Ok(_) => panic!("try_upgrade should not succeed with another reader"),
+        };
+        assert!(!lock.is_write_locked());
+        drop(lock.try_read().unwrap());
+
+        drop(read_guard);
+        let mut write_guard = upgradable.try_upgrade().ok().unwrap();
+        *write_guard = 1;
+        assert!(lock.try_read().is_err());
+        drop(write_guard);
+
+        assert_eq!(*lock.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn upgrade_waits_for_readers() {
+        let lock = QueuedRwLock::new(0);
+        let upgradable = lock.upgradable_read().unwrap();
+        let read_guard = lock.read().unwrap();
+
+        thread::scope(|s| {
+            // Queued behind the upgradable reader so has to see its write
+            let writer = s.spawn(|| {
+                let mut guard = lock.write().unwrap();
+                *guard *= 2;
+            });
+
+            let upgrader = s.spawn(move || {
+                let mut guard = upgradable.upgrade();
+                *guard += 1;
+            });
+
+            thread::sleep(Duration::from_millis(10));
+            drop(read_guard);
+            upgrader.join().unwrap();
+            writer.join().unwrap();
+        });
+
+        assert_eq!(*lock.read().unwrap(), 2);
+    }
+
+    #[test]
+    fn map_read() {
+        let lock = QueuedRwLock::new((1, String::from("two")));
+        let guard = QueuedRwLockReadGuard::map(lock.read().unwrap(), |data| &data.1);
+        let guard = QueuedRwLockMappedReadGuard::map(guard, |data| data.as_str());
+        assert_eq!(&*guard, "two");
+
+        // Still counts as a reader until dropped
+        assert!(lock.try_write().is_err());
+        drop(guard);
+        drop(lock.write().unwrap());
+    }
+
+    #[test]
+    fn map_write() {
+        let lock = QueuedRwLock::new((1, vec![2]));
+        let guard = QueuedRwLockWriteGuard::map(lock.write().unwrap(), |data| &mut data.1);
+        let mut guard = QueuedRwLockMappedWriteGuard::map(guard, |data| &mut data[0]);
+        *guard = 3;
+
+        assert!(lock.try_read().is_err());
+        drop(guard);
+        assert_eq!(*lock.read().unwrap(), (1, vec![3]));
+    }
+
+    #[test]
+    fn as_ref() {
+        fn sum(numbers: impl AsRef<Vec<i32>>) -> i32 {
+            numbers.as_ref().iter().sum()
+        }
+
+        fn push(mut numbers: impl AsMut<Vec<i32>>) {
+            numbers.as_mut().push(3);
+        }
+
+        let lock = QueuedRwLock::new((0, vec![1, 2]));
+        push(QueuedRwLockWriteGuard::map(lock.write().unwrap(), |data| &mut data.1));
+        assert_eq!(sum(QueuedRwLockReadGuard::map(lock.read().unwrap(), |data| &data.1)), 6);
+
+        let lock = QueuedRwLock::new(vec![1]);
+        push(lock.write().unwrap());
+        assert_eq!(lock.write().unwrap().as_ref().len(), 2);
+        assert_eq!(sum(lock.read().unwrap()), 4);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn map_write_poisons() {
+        let lock = QueuedRwLock::new((1, 2));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                let _guard = QueuedRwLockWriteGuard::map(lock.write().unwrap(), |data| &mut data.0);
+                panic!("poison the lock");
+            }).join().unwrap_err();
+
+            s.spawn(|| {
+                let _guard = QueuedRwLockWriteGuard::map(lock.write().unwrap_or_else(PoisonError::into_inner),
+                                                         |_| -> &mut i32 { panic!("panic while mapping") });
+            }).join().unwrap_err();
+        });
+
+        assert!(lock.is_poisoned());
+        assert!(lock.write().is_err());
+    }
+
+    // Without std the mutex and condvars spin, which has to keep the queue
+    // in order the same, and a panic can't be told apart from a normal drop
+    #[cfg(not(feature = "std"))]
+    #[test]
+    fn spin_backend() {
+        let lock = QueuedRwLock::new(Vec::new());
+        let write_guard = lock.write().unwrap();
+
+        thread::scope(|s| {
+            for i in 0..4 {
+                let lock = &lock;
+                s.spawn(move || lock.write().unwrap().push(i));
+                while lock.queue_len() != i + 1 {
+                    thread::yield_now();
+                }
+            }
+
+            drop(write_guard);
+        });
+        assert_eq!(*lock.read().unwrap(), [0, 1, 2, 3]);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                let _guard = lock.write().unwrap();
+                panic!("doesn't poison without std");
+            }).join().unwrap_err();
+        });
+        assert!(!lock.is_poisoned());
+        assert_eq!(lock.read().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn reader_count() {
+        let lock = QueuedRwLock::new(0);
+        assert_eq!(lock.reader_count(), 0);
+        assert!(!lock.is_write_locked());
+
+        let guards = (lock.read().unwrap(), lock.upgradable_read().unwrap());
+        assert_eq!(lock.reader_count(), 2);
+        assert!(!lock.is_write_locked());
+        drop(guards);
+
+        let write_guard = lock.write().unwrap();
+        assert_eq!(lock.reader_count(), 0);
+        assert!(lock.is_write_locked());
+        drop(write_guard);
+    }
+
+    #[test]
+    fn reader_count_padded() {
+        // The reader count can only have a cache line to itself if the raw
+        // lock is at least that aligned and nothing gets packed in after it
+        assert!(mem::align_of::<RawQueuedRwLock>() >= 64);
+        assert_eq!(mem::size_of::<RawQueuedRwLock>() % 64, 0);
+        assert!(mem::align_of::<QueuedRwLock<u8>>() >= 64);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn queue_len() {
+        let lock = QueuedRwLock::new(0);
+        assert_eq!(lock.queue_len(), 0);
+
+        let write_guard = lock.write().unwrap();
+        assert_eq!(lock.queue_len(), 0);
+
+        let first = lock.take_ticket();
+        let second = lock.take_ticket();
+        assert_eq!(lock.queue_len(), 2);
+
+        assert!(lock.write_timeout(Duration::from_millis(1)).is_err());
+        assert_eq!(lock.queue_len(), 2);
+
+        drop(write_guard);
+        drop(first);
+        assert_eq!(lock.queue_len(), 1);
+        drop(second);
+        assert_eq!(lock.queue_len(), 0);
+    }
+
+    #[test]
+    fn guard_fmt() {
+        let lock = QueuedRwLock::new(String::from("data"));
+
+        {
+            let guard = lock.read().unwrap();
+            assert_eq!(format!("{:?}", guard), "\"data\"");
+            assert_eq!(format!("{}", guard), "data");
+        }
+
+        {
+            let guard = lock.write().unwrap();
+            assert_eq!(format!("{:?} {}", guard, guard), "\"data\" data");
+
+            let ticket = lock.take_ticket();
+            let debug = format!("{:?}", ticket);
+            ticket.cancel();
+            assert_eq!(debug, "QueuedRwLockTicketGuard { ticket: 1, position: 0 }");
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let lock = QueuedRwLock::new(vec![1u32, 2, 3]);
+        let json = serde_json::to_string(&lock).unwrap();
+        assert_eq!(json, "[1,2,3]");
+
+        let lock: QueuedRwLock<Vec<u32>> = serde_json::from_str(&json).unwrap();
+        assert_eq!(*lock.read().unwrap(), [1, 2, 3]);
+
+        if cfg!(feature = "std") {
+            let _ = thread::scope(|s| {
+                s.spawn(|| {
+                    let _guard = lock.write().unwrap();
+                    panic!();
+                }).join()
+            });
+            assert!(serde_json::to_string(&lock).is_err());
+        }
+    }
+
+    #[test]
+    fn read_with_write_with() {
+        let lock = QueuedRwLock::new(vec![1]);
+        assert_eq!(lock.write_with(|data| {
+            data.push(2);
+            data.len()
+        }).unwrap(), 2);
+        assert_eq!(lock.read_with(|data| data.clone()).unwrap(), [1, 2]);
+        assert_eq!(lock.reader_count(), 0);
+        assert!(!lock.is_write_locked());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn write_with_poisons() {
+        let lock = QueuedRwLock::new(0);
+
+        let _ = thread::scope(|s| s.spawn(|| lock.write_with(|_| panic!())).join());
+        assert!(lock.is_poisoned());
+
+        match lock.read_with(|data| *data) {
+            Err(err) => assert_eq!(err.into_inner(), 0),
+            Ok(_) => panic!("read_with should report the poison"),
+        }
+    }
+
+    #[test]
+    fn write_if() {
+        let lock = QueuedRwLock::new(0);
+        let bump = || lock.write_if(|n| *n < 2, |n| {
+            *n += 1;
+            *n
+        }).unwrap();
+
+        assert_eq!(bump(), Some(1));
+        assert_eq!(bump(), Some(2));
+        assert_eq!(bump(), None);
+        assert_eq!(*lock.read().unwrap(), 2);
+        assert!(!lock.is_write_locked());
+    }
+
+    #[test]
+    fn read_retry() {
+        let reset = |n: &mut i32| {
+            *n = 0;
+            true
+        };
+
+        let lock = QueuedRwLock::new(-1);
+        assert_eq!(lock.read_retry(1, reset, |n| *n).unwrap(), -1);
+
+        lock.poison();
+        assert_eq!(lock.read_retry(1, reset, |n| *n).unwrap(), 0);
+        assert!(!lock.is_poisoned());
+
+        // Never put right, so it gives up
+        let mut recoveries = 0;
+        lock.poison();
+        let result = lock.read_retry(3, |n| {
+            recoveries += 1;
+            *n = recoveries;
+            false
+        }, |n| *n);
+        assert_eq!(result.unwrap_err().into_inner(), 3);
+        assert_eq!(recoveries, 3);
+    }
+
+    #[test]
+    fn partial_eq() {
+        let a = QueuedRwLock::new(1);
+        let b = QueuedRwLock::new(1);
+        assert!(a == b);
+        assert!(b == a);
+
+        *b.write().unwrap() = 2;
+        assert!(a != b);
+
+        // Poisoned data still compares
+        a.poison();
+        assert!(a == QueuedRwLock::new(1));
+        assert!(a != b);
+
+        // Comparing with itself doesn't wait on the queue
+        let ticket = a.take_ticket();
+        assert!(PartialEq::eq(&a, &a));
+        ticket.cancel();
+    }
+
+    #[test]
+    fn swap() {
+        let lock = QueuedRwLock::new(String::from("old"));
+        assert_eq!(lock.swap(String::from("new")).unwrap(), "old");
+        lock.store(String::from("newer")).unwrap();
+        assert_eq!(*lock.read().unwrap(), "newer");
+
+        lock.poison();
+        let old = lock.swap(String::from("newest")).unwrap_err().into_inner();
+        assert_eq!(old, "newer");
+        assert_eq!(*lock.read().unwrap_err().into_inner(), "newest");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn replace_with() {
+        let lock = QueuedRwLock::new(1);
+        assert_eq!(lock.replace_with(|n| n * 10).unwrap(), 1);
+        assert_eq!(*lock.read().unwrap(), 10);
+
+        let _ = thread::scope(|s| {
+            s.spawn(|| lock.replace_with(|_| panic!())).join()
+        });
+        assert!(lock.is_poisoned());
+        assert_eq!(*lock.read().unwrap_err().into_inner(), 10);
+    }
+
+    #[test]
+    fn read_map_collect() {
+        let lock = QueuedRwLock::new(vec![1, 2, 3]);
+        let doubled: Vec<i32> = lock.read_map_collect(|x| x * 2).unwrap();
+        assert_eq!(doubled, [2, 4, 6]);
+        assert_eq!(lock.reader_count(), 0);
+    }
+
+    #[test]
+    fn default() {
+        let lock: QueuedRwLock<Vec<u8>> = Default::default();
+        assert!(lock.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn from() {
+        let locks: Vec<QueuedRwLock<i32>> = vec![1, 2].into_iter().map(Into::into).collect();
+        assert_eq!(*locks[1].read().unwrap(), 2);
+    }
+
+    #[test]
+    fn clone() {
+        let lock = QueuedRwLock::new(vec![1]);
+        let clone = lock.clone();
+
+        // Holding one doesn't lock the other
+        let mut guard = lock.write().unwrap();
+        guard.push(2);
+        clone.write().unwrap().push(3);
+        drop(guard);
+        assert_eq!(*lock.read().unwrap(), [1, 2]);
+        assert_eq!(*clone.read().unwrap(), [1, 3]);
+
+        lock.poison();
+        let clone = lock.clone();
+        assert_eq!(*clone.read().unwrap_err().into_inner(), [1, 2]);
+        clone.clear_poison();
+        assert!(lock.is_poisoned());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn clone_keeps_poison_cause() {
+        let lock = QueuedRwLock::new(0);
+        let _ = thread::scope(|s| {
+            s.spawn(|| {
+                let mut guard = lock.write().unwrap();
+                guard.set_poison_context("bumping");
+                panic!();
+            }).join()
+        });
+
+        let clone = lock.clone();
+        let cause = clone.poison_cause().unwrap();
+        assert!(cause.panicked());
+        assert_eq!(cause.context(), Some("bumping"));
+        assert_eq!(clone.poison_cause(), lock.poison_cause());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn clear_poison() {
+        let lock = QueuedRwLock::new(0);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mut guard = lock.write().unwrap();
+                *guard = -1;
+                panic!("poison the lock");
+            }).join().unwrap_err();
+        });
+
+        let mut guard = lock.write().unwrap_or_else(PoisonError::into_inner);
+        *guard = 0;
+        lock.clear_poison();
+        drop(guard);
+
+        assert!(!lock.is_poisoned());
+        assert_eq!(*lock.read().unwrap(), 0);
+    }
+
+    #[test]
+    fn cancel_ticket() {
+        let lock = QueuedRwLock::new(0);
+        let read_guard = lock.read().unwrap();
+
+        // Would block on the reader if it had to take the lock
+        lock.take_ticket().cancel();
+        drop(lock.try_read().unwrap());
+        drop(read_guard);
+
+        let write_guard = lock.write().unwrap();
+        let cancelled = lock.take_ticket();
+
+        thread::scope(|s| {
+            let writer = s.spawn(|| *lock.write().unwrap() += 1);
+
+            cancelled.cancel();
+            drop(write_guard);
+            writer.join().unwrap();
+        });
+
+        assert_eq!(lock.queue_len(), 0);
+        assert_eq!(*lock.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn ticket_across_threads() {
+        let lock = QueuedRwLock::new(Vec::new());
+        let write_guard = lock.write().unwrap();
+        let first = lock.take_ticket();
+        let second = lock.take_ticket();
+        let third = lock.take_ticket();
+
+        thread::scope(|s| {
+            let first = s.spawn(move || first.write().unwrap().push(1));
+            // Dropped without writing, still has to wait its turn
+            let second = s.spawn(move || drop(second));
+            let third = s.spawn(move || third.write().unwrap().push(3));
+
+            thread::sleep(Duration::from_millis(10));
+            drop(write_guard);
+            first.join().unwrap();
+            second.join().unwrap();
+            third.join().unwrap();
+        });
+
+        assert_eq!(*lock.read().unwrap(), [1, 3]);
+        assert_eq!(lock.queue_len(), 0);
+    }
+
+    #[test]
+    fn ticket_position() {
+        let lock = QueuedRwLock::new(0);
+        let write_guard = lock.write().unwrap();
+
+        let first = lock.take_ticket();
+        let second = lock.take_ticket();
+        let third = lock.take_ticket();
+        assert_eq!((first.position(), second.position(), third.position()), (0, 1, 2));
+
+        second.cancel();
+        assert_eq!((first.position(), third.position()), (0, 1));
+
+        drop(write_guard);
+        drop(first);
+        assert_eq!(third.position(), 0);
+    }
+
+    #[test]
+    fn ticket_handle() {
+        let lock = QueuedRwLock::new(0);
+        let write_guard = lock.write().unwrap();
+
+        let first = lock.take_ticket();
+        let second = lock.take_ticket();
+        let handles = [first.handle(), second.handle()];
+        assert_eq!(handles.map(|handle| handle.position()), [0, 1]);
+        assert!(handles[0].is_current() && !handles[1].is_current());
+
+        drop(write_guard);
+        *first.write().unwrap() += 1;
+        assert!(handles[1].is_current());
+
+        second.cancel();
+        assert!(handles.iter().all(|handle| handle.is_current()));
+    }
+
+    #[test]
+    fn read_into_write_ticket() {
+        let lock = QueuedRwLock::new(0);
+        let guard = lock.read().unwrap();
+        let ticket = guard.into_write_ticket();
+        assert_eq!(lock.reader_count(), 0);
+
+        let later = lock.take_ticket();
+        assert_eq!((ticket.position(), later.position()), (0, 1));
+
+        *ticket.write().unwrap() = 1;
+        *later.write().unwrap() += 1;
+        assert_eq!(*lock.read().unwrap(), 2);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn arc_guards() {
+        let lock = QueuedRwLock::new_arc(0);
+
+        let guard = lock.write_arc().unwrap();
+        let handle = thread::spawn(move || {
+            let mut guard = guard;
+            *guard = 1;
+        });
+        handle.join().unwrap();
+        assert!(!lock.is_write_locked());
+
+        let guard = lock.read_arc().unwrap();
+        assert_eq!(*guard, 1);
+        assert!(Arc::ptr_eq(QueuedRwLockArcReadGuard::lock(&guard), &lock));
+        drop(lock);
+        assert_eq!(*guard, 1);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn arc_write_guard_waits_behind_ticket() {
+        let lock = QueuedRwLock::new_arc(0);
+        let ticket = lock.take_ticket();
+
+        let handle = {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                let mut guard = lock.write_arc().unwrap();
+                *guard += 1;
+            })
+        };
+        while lock.queue_len() < 2 {
+            thread::yield_now();
+        }
+
+        // Dropping an unused ticket still has to let the next one through
+        drop(ticket);
+        handle.join().unwrap();
+
+        assert_eq!(*lock.read().unwrap(), 1);
+        assert_eq!(lock.queue_len(), 0);
+        assert_eq!(Arc::strong_count(&lock), 1);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn arc_write_guard_poisons() {
+        let lock = QueuedRwLock::new_arc(0);
+        let guard = lock.write_arc().unwrap();
+        let _ = thread::spawn(move || {
+            let _guard = guard;
+            panic!();
+        }).join();
+
+        assert!(lock.is_poisoned());
+        assert!(lock.read_arc().is_err());
+    }
+
+    #[test]
+    fn write_bump() {
+        let lock = QueuedRwLock::new(0);
+        let mut guard = lock.write().unwrap();
+        *guard = 1;
+
+        thread::scope(|s| {
+            s.spawn(|| *lock.write().unwrap() *= 10);
+            while lock.queue_len() == 0 {
+                thread::yield_now();
+            }
+
+            guard.bump();
+            assert_eq!(*guard, 10);
+            *guard += 1;
+        });
+
+        drop(guard);
+        assert_eq!(*lock.read().unwrap(), 11);
+    }
+
+    #[test]
+    fn write_unlocked() {
+        let lock = QueuedRwLock::new(0);
+        let mut guard = lock.write().unwrap();
+        *guard = 1;
+
+        let seen = guard.unlocked(|| {
+            let mut inner = lock.write().unwrap();
+            *inner += 1;
+            *inner
+        });
+        assert_eq!(seen, 2);
+        assert_eq!(*guard, 2);
+        assert!(lock.try_read().is_err());
     }
-}
 
-unsafe impl<'a, T: Send> Send for QueuedRwLockTicketGuard<'a, T> {}
-unsafe impl<'a, T: Sync> Sync for QueuedRwLockTicketGuard<'a, T> {}
+    #[cfg(feature = "std")]
+    #[test]
+    fn write_unlocked_panic_relocks() {
+        use std::panic::{self, AssertUnwindSafe};
 
-impl<'a, T> Drop for QueuedRwLockTicketGuard<'a, T> {
-    fn drop(&mut self) {
-        // This will only be called if we didn't take the lock, have to do so
-        // or we stall other users forever
-        self.lock.inner.write(self.ticket);
-        self.lock.inner.write_unlock();
+        let lock = QueuedRwLock::new(0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut guard = lock.write().unwrap();
+            guard.unlocked(|| panic!());
+        }));
+
+        assert!(result.is_err());
+        assert!(lock.is_poisoned());
+        assert!(!lock.is_write_locked());
+        assert_eq!(lock.queue_len(), 0);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::sync::TryLockError;
-    use super::*;
+    #[test]
+    fn ticket_try_write() {
+        let lock = QueuedRwLock::new(0);
+        let read_guard = lock.read().unwrap();
+        let first = lock.take_ticket();
+        let second = lock.take_ticket();
+
+        let first = first.try_write().err().unwrap();
+        drop(read_guard);
+        let second = second.try_write().err().unwrap();
+        assert_eq!(second.position(), 1);
+
+        *first.try_write().ok().unwrap().unwrap() = 1;
+        assert_eq!(*second.try_write().ok().unwrap().unwrap(), 1);
+        assert_eq!(lock.queue_len(), 0);
+    }
 
+    #[cfg(feature = "stats")]
     #[test]
-    fn smoke() {
-        let lock = QueuedRwLock::new(());
-        drop(lock.read().unwrap());
-        drop(lock.write().unwrap());
-        drop((lock.read().unwrap(), lock.read().unwrap()));
-        drop(lock.write().unwrap());
+    fn stats() {
+        let lock = QueuedRwLock::new(0);
+        assert_eq!(lock.stats(), LockStats::default());
+
+        for _ in 0..10 {
+            drop(lock.read().unwrap());
+        }
+        for _ in 0..5 {
+            *lock.write().unwrap() += 1;
+        }
+
+        let write_guard = lock.write().unwrap();
+        let tickets: Vec<_> = (0..3).map(|_| lock.take_ticket()).collect();
+        drop(write_guard);
+        drop(tickets);
+
+        let stats = lock.stats();
+        assert_eq!((stats.reads, stats.writes), (10, 9));
+        assert_eq!(stats.max_queue_len, 3);
     }
 
+    #[cfg(feature = "stats")]
     #[test]
-    fn try_read() {
+    fn avg_queue_depth() {
         let lock = QueuedRwLock::new(0);
+        assert_eq!(lock.avg_queue_depth(), 0.0);
+
         let write_guard = lock.write().unwrap();
+        let mut tickets = Vec::new();
+        let mut last = 0.0;
+        for _ in 0..5 {
+            tickets.push(lock.take_ticket());
+            assert!(lock.avg_queue_depth() > last);
+            last = lock.avg_queue_depth();
+        }
+        assert!(last < 5.0);
 
-        let read_result = lock.try_read();
-        match read_result {
-            Err(TryLockError::WouldBlock) => (),
-            Ok(_) => {
-                assert!(false,
-                        "try_read should not succeed while write_guard is in scope")
-            }
-            Err(_) => assert!(false, "unexpected error"),
+        // Lags behind the queue emptying, then falls while it stays short
+        drop(write_guard);
+        drop(tickets);
+        last = lock.avg_queue_depth();
+        for _ in 0..5 {
+            *lock.write().unwrap() += 1;
+            assert!(lock.avg_queue_depth() < last);
+            last = lock.avg_queue_depth();
         }
+        assert!(last > 0.0);
+    }
+
+    #[cfg(all(feature = "stats", feature = "std"))]
+    #[test]
+    fn stats_write_wait() {
+        let lock = QueuedRwLock::new(0);
+        let read_guard = lock.read().unwrap();
+
+        thread::scope(|s| {
+            let writer = s.spawn(|| *lock.write().unwrap() = 1);
+            thread::sleep(Duration::from_millis(20));
+            drop(read_guard);
+            writer.join().unwrap();
+        });
+
+        assert!(lock.stats().write_wait >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn ticket_readers_ahead() {
+        let lock = QueuedRwLock::new(0);
+        let first = lock.read().unwrap();
+        let second = lock.read().unwrap();
+
+        let ticket = lock.take_ticket();
+        assert_eq!(ticket.readers_ahead(), 2);
+
+        drop(first);
+        assert_eq!(ticket.readers_ahead(), 1);
+
+        drop(second);
+        drop(ticket.write().unwrap());
+    }
+
+    #[cfg(all(feature = "lock_api", feature = "std"))]
+    #[test]
+    fn lock_api() {
+        type RwLock<T> = ::lock_api::RwLock<RawQueuedRwLock, T>;
+
+        let lock = RwLock::new(0);
+        *lock.write() = 1;
+        assert_eq!(*lock.read(), 1);
+
+        let read_guard = lock.read();
+        assert!(lock.try_write_for(Duration::from_millis(1)).is_none());
+        drop(read_guard);
+
+        let write_guard = lock.write();
+        assert!(lock.try_read().is_none());
+        let read_guard = ::lock_api::RwLockWriteGuard::downgrade(write_guard);
+        assert!(lock.try_read().is_some());
+        drop(read_guard);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn writer_not_starved_by_readers() {
+        let lock = QueuedRwLock::new(0);
+        let done = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            // Overlapping readers so there's never a moment without one
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while !done.load(Ordering::Relaxed) {
+                        let _guard = lock.read().unwrap();
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                });
+            }
+
+            thread::sleep(Duration::from_millis(10));
+            let result = lock.write_timeout(Duration::from_secs(5)).map(|mut guard| *guard = 1);
+            done.store(true, Ordering::Relaxed);
+            assert!(result.is_ok(), "writer starved by readers");
+        });
+    }
+
+    #[test]
+    fn readers_wait_behind_queued_writer() {
+        let lock = QueuedRwLock::new(0);
+        let write_guard = lock.write().unwrap();
+        let ticket = lock.take_ticket();
 
         drop(write_guard);
+        assert!(lock.try_read().is_err());
+
+        ticket.cancel();
+        drop(lock.try_read().unwrap());
+    }
+
+    // A reader starts waiting on a writer and then another writer queues up
+    // behind it, returns who got in first once the lock was free
+    fn order_after_write(fairness: FairnessMode) -> Vec<&'static str> {
+        use std::sync::Mutex;
+
+        let lock = QueuedRwLock::with_fairness((), fairness);
+        let order = Mutex::new(Vec::new());
+        let write_guard = lock.write().unwrap();
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                let _guard = lock.read().unwrap();
+                order.lock().unwrap().push("read");
+            });
+            thread::sleep(Duration::from_millis(10));
+
+            s.spawn(|| {
+                let _guard = lock.write().unwrap();
+                order.lock().unwrap().push("write");
+            });
+            while lock.queue_len() == 0 {
+                thread::yield_now();
+            }
+            thread::sleep(Duration::from_millis(10));
+
+            drop(write_guard);
+        });
+
+        order.into_inner().unwrap()
     }
 
     #[test]
-    fn try_write() {
+    fn fifo() {
+        assert_eq!(order_after_write(FairnessMode::Fifo), ["read", "write"]);
+    }
+
+    #[test]
+    fn alternating() {
+        use std::sync::Mutex;
+
+        // Queues up behind a held write lock in the order given, returns who
+        // got in in what order
+        fn order(fairness: FairnessMode) -> Vec<&'static str> {
+            let lock = QueuedRwLock::with_fairness((), fairness);
+            let order = Mutex::new(Vec::new());
+            let write_guard = lock.write().unwrap();
+
+            thread::scope(|s| {
+                let (mut writers, mut readers) = (0, 0);
+                for &name in &["write a", "read a", "write b", "read b"] {
+                    let (lock, order) = (&lock, &order);
+                    s.spawn(move || {
+                        if name.starts_with("read") {
+                            let _guard = lock.read().unwrap();
+                            order.lock().unwrap().push(name);
+                        } else {
+                            let _guard = lock.write().unwrap();
+                            order.lock().unwrap().push(name);
+                        }
+                    });
+
+                    // Wait for it to queue up before spawning the next one
+                    if name.starts_with("read") {
+                        readers += 1;
+                    } else {
+                        writers += 1;
+                    }
+                    while lock.queue_len() != writers || lock.inner.parked_readers() != readers {
+                        thread::yield_now();
+                    }
+                }
+
+                drop(write_guard);
+            });
+
+            order.into_inner().unwrap()
+        }
+
+        assert_eq!(order(FairnessMode::Fifo), ["write a", "read a", "write b", "read b"]);
+
+        let mut alternating = order(FairnessMode::Alternating);
+        alternating[1..3].sort();
+        assert_eq!(alternating, ["write a", "read a", "read b", "write b"]);
+    }
+
+    #[test]
+    fn writer_preferring() {
+        assert_eq!(order_after_write(FairnessMode::WriterPreferring), ["write", "read"]);
+    }
+
+    #[test]
+    fn reader_preferring() {
+        let lock = QueuedRwLock::with_fairness(0, FairnessMode::ReaderPreferring);
+        let read_guard = lock.read().unwrap();
+
+        thread::scope(|s| {
+            let writer = s.spawn(|| *lock.write().unwrap() = 1);
+            while lock.queue_len() == 0 {
+                thread::yield_now();
+            }
+            thread::sleep(Duration::from_millis(10));
+
+            assert!(!lock.is_write_locked());
+            let second = lock.try_read().unwrap();
+            drop(read_guard);
+            assert!(!lock.is_write_locked());
+
+            drop(second);
+            writer.join().unwrap();
+        });
+
+        assert_eq!(*lock.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn write_ratio() {
+        let lock = QueuedRwLock::with_write_ratio(0, 2);
+        let read_guard = lock.read().unwrap();
+
+        thread::scope(|s| {
+            let writer = s.spawn(|| *lock.write().unwrap() = 1);
+            while lock.queue_len() == 0 {
+                thread::yield_now();
+            }
+
+            let second = lock.try_read().unwrap();
+            let third = lock.try_read().unwrap();
+            assert!(lock.try_read().is_err());
+            assert!(!lock.is_write_locked());
+
+            drop((read_guard, second, third));
+            writer.join().unwrap();
+        });
+
+        assert_eq!(*lock.try_read().unwrap(), 1);
+    }
+
+    #[test]
+    fn write_ratio_under_contention() {
+        use std::sync::atomic::AtomicUsize;
+
+        const WRITES: usize = 20;
+
+        let lock = QueuedRwLock::with_write_ratio(0, 4);
+        let done = AtomicBool::new(false);
+        let reads = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while !done.load(Ordering::Relaxed) {
+                        let _guard = lock.read().unwrap();
+                        reads.fetch_add(1, Ordering::Relaxed);
+                        thread::yield_now();
+                    }
+                });
+            }
+
+            // Readers overlap the whole time, only the ratio lets the
+            // writer in
+            while reads.load(Ordering::Relaxed) < 100 {
+                thread::yield_now();
+            }
+            for _ in 0..WRITES {
+                *lock.write().unwrap() += 1;
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+
+        assert_eq!(*lock.read().unwrap(), WRITES);
+    }
+
+    #[test]
+    fn read_recursive() {
+        let lock = QueuedRwLock::new(0);
+        let outer = lock.read().unwrap();
+
+        thread::scope(|s| {
+            let writer = s.spawn(|| *lock.write().unwrap() = 1);
+            while lock.queue_len() == 0 && !lock.is_write_locked() {
+                thread::yield_now();
+            }
+
+            assert!(lock.try_read().is_err());
+            let inner = lock.read_recursive().unwrap();
+            assert_eq!(*inner, 0);
+
+            drop(inner);
+            drop(outer);
+            writer.join().unwrap();
+        });
+
+        assert_eq!(*lock.read_recursive().unwrap(), 1);
+    }
+
+    #[cfg(all(feature = "deadlock_detection", debug_assertions))]
+    #[test]
+    fn nested_read_panics() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let lock = QueuedRwLock::new(0);
+        let outer = lock.read().unwrap();
+
+        thread::scope(|s| {
+            let writer = s.spawn(|| *lock.write().unwrap() = 1);
+            while lock.queue_len() == 0 && !lock.is_write_locked() {
+                thread::yield_now();
+            }
+
+            let nested = panic::catch_unwind(AssertUnwindSafe(|| drop(lock.read())));
+            assert!(nested.is_err());
+
+            drop(outer);
+            writer.join().unwrap();
+        });
+
+        // Nothing's waiting so nesting is fine
+        let outer = lock.read().unwrap();
+        assert_eq!(*lock.read().unwrap(), *outer);
+    }
+
+    #[cfg(all(feature = "deadlock_detection", debug_assertions))]
+    #[test]
+    fn read_dropped_on_another_thread() {
+        let lock = QueuedRwLock::new(0);
+        let guard = lock.read().unwrap();
+
+        thread::scope(|s| {
+            s.spawn(move || drop(guard)).join().unwrap();
+
+            let writer = s.spawn(|| {
+                let mut guard = lock.write().unwrap();
+                thread::sleep(Duration::from_millis(10));
+                *guard = 1;
+            });
+            while !lock.is_write_locked() {
+                thread::yield_now();
+            }
+
+            // The read this thread took is gone, so it's fine to wait
+            assert_eq!(*lock.read().unwrap(), 1);
+            writer.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn read_contended() {
         let lock = QueuedRwLock::new(0);
+        let (guard, waited) = lock.read_contended().unwrap();
+        assert!(!waited);
+        drop(guard);
+
+        let write_guard = lock.write().unwrap();
+        thread::scope(|s| {
+            let reader = s.spawn(|| lock.read_contended().unwrap().1);
+            thread::sleep(Duration::from_millis(10));
+            drop(write_guard);
+            assert!(reader.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn write_contended() {
+        let lock = QueuedRwLock::new(0);
+        let (guard, waited) = lock.write_contended().unwrap();
+        assert!(!waited);
+        drop(guard);
+
+        let write_guard = lock.write().unwrap();
+        thread::scope(|s| {
+            let writer = s.spawn(|| lock.write_contended().unwrap().1);
+            thread::sleep(Duration::from_millis(10));
+            drop(write_guard);
+            assert!(writer.join().unwrap());
+        });
+
+        // Waiting for readers to leave counts too
         let read_guard = lock.read().unwrap();
+        thread::scope(|s| {
+            let writer = s.spawn(|| lock.write_contended().unwrap().1);
+            thread::sleep(Duration::from_millis(10));
+            drop(read_guard);
+            assert!(writer.join().unwrap());
+        });
+    }
 
-        let write_result = lock.try_write();
-        match write_result {
-            Err(TryLockError::WouldBlock) => (),
-            Ok(_) => {
-                assert!(false,
-                        "try_write should not succeed while read_guard is in scope")
+    #[test]
+    fn read_impatient() {
+        let lock = QueuedRwLock::new(0);
+        let upgradable = lock.upgradable_read().unwrap();
+
+        thread::scope(|s| {
+            let writer = s.spawn(|| *lock.write().unwrap() = 1);
+            while lock.queue_len() == 0 {
+                thread::yield_now();
+            }
+
+            assert!(lock.try_read().is_err());
+            let guard = lock.read_impatient().unwrap();
+            assert_eq!(*guard, 0);
+
+            drop(guard);
+            drop(upgradable);
+            writer.join().unwrap();
+        });
+
+        let guard = lock.write().unwrap();
+        thread::scope(|s| {
+            let reader = s.spawn(|| *lock.read_impatient().unwrap());
+            thread::sleep(Duration::from_millis(10));
+            assert!(!reader.is_finished());
+            drop(guard);
+            assert_eq!(reader.join().unwrap(), 1);
+        });
+    }
+
+    #[test]
+    fn stress() {
+        let lock = QueuedRwLock::new((0, 0));
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let mut guard = lock.write().unwrap();
+                        guard.0 += 1;
+                        guard.1 += 1;
+                    }
+                });
+            }
+
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..10000 {
+                        let guard = lock.read().unwrap();
+                        assert_eq!(guard.0, guard.1);
+                        drop(guard);
+
+                        if let Ok(guard) = lock.try_read() {
+                            assert_eq!(guard.0, guard.1);
+                        }
+                    }
+                });
+            }
+        });
+
+        assert_eq!(*lock.read().unwrap(), (4000, 4000));
+        assert_eq!(lock.reader_count(), 0);
+    }
+
+    // Run with --ignored --nocapture to compare against std
+    #[test]
+    #[ignore]
+    fn read_throughput() {
+        use std::sync::RwLock;
+        use std::time::Instant;
+
+        const THREADS: usize = 8;
+        const READS: usize = 1_000_000;
+
+        let lock = QueuedRwLock::new(0);
+        let start = Instant::now();
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..READS {
+                        drop(lock.read().unwrap());
+                    }
+                });
+            }
+        });
+        let queued = start.elapsed();
+
+        let lock = RwLock::new(0);
+        let start = Instant::now();
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..READS {
+                        drop(lock.read().unwrap());
+                    }
+                });
+            }
+        });
+        let std = start.elapsed();
+
+        println!("QueuedRwLock: {:?}, std RwLock: {:?}", queued, std);
+    }
+
+    // Run with --ignored --nocapture to compare spinning against sleeping
+    // straight away when the lock is passed back and forth quickly
+    #[test]
+    #[ignore]
+    fn spin_latency() {
+        use std::time::Instant;
+
+        const THREADS: usize = 4;
+        const WRITES: usize = 100_000;
+
+        fn run(lock: &QueuedRwLock<usize>) -> Duration {
+            let start = Instant::now();
+            thread::scope(|s| {
+                for _ in 0..THREADS {
+                    s.spawn(|| {
+                        for _ in 0..WRITES {
+                            *lock.write().unwrap() += 1;
+                        }
+                    });
+                }
+            });
+            start.elapsed()
+        }
+
+        let spinning = run(&QueuedRwLock::new(0));
+        let sleeping = run(&QueuedRwLock::with_spin_count(0, 0));
+
+        println!("spinning: {:?}, sleeping: {:?}", spinning, sleeping);
+    }
+
+    // Run with --ignored --nocapture to see how long it takes to wake the
+    // next writer in line
+    #[test]
+    #[ignore]
+    fn handoff_latency() {
+        use std::time::Instant;
+
+        const WRITES: u32 = 100_000;
+
+        let lock = QueuedRwLock::with_spin_count(0, 0);
+        let start = Instant::now();
+        thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..WRITES {
+                        *lock.write().unwrap() += 1;
+                    }
+                });
             }
-            Err(_) => assert!(false, "unexpected error"),
+        });
+
+        println!("per write: {:?}", start.elapsed() / (2 * WRITES));
+    }
+
+    #[test]
+    fn spin_count() {
+        let lock = QueuedRwLock::with_spin_count(0, 0);
+        let read_guard = lock.read().unwrap();
+
+        thread::scope(|s| {
+            let writer = s.spawn(|| *lock.write().unwrap() = 1);
+            thread::sleep(Duration::from_millis(10));
+            drop(read_guard);
+            writer.join().unwrap();
+        });
+
+        assert_eq!(*lock.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn data_ptr() {
+        let lock = QueuedRwLock::new(0);
+        let ptr = lock.data_ptr();
+
+        {
+            let _guard = lock.write().unwrap();
+            unsafe { *ptr = 1 };
         }
 
-        drop(read_guard);
+        let guard = lock.read().unwrap();
+        assert_eq!(&*guard as *const i32, ptr as *const i32);
+        assert_eq!(*guard, 1);
     }
 
     #[test]
@@ -261,4 +4217,20 @@ mod tests {
         let lock = QueuedRwLock::new(NonCopy(10));
         assert_eq!(lock.into_inner().unwrap(), NonCopy(10));
     }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn data_mut_unchecked_ignores_poison() {
+        let mut lock = QueuedRwLock::new(0);
+        let _ = thread::scope(|s| {
+            s.spawn(|| {
+                let _guard = lock.write().unwrap();
+                panic!();
+            }).join()
+        });
+
+        *lock.data_mut_unchecked() += 1;
+        assert!(lock.is_poisoned());
+        assert_eq!(lock.into_inner().unwrap_err().into_inner(), 1);
+    }
 }
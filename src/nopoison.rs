@@ -0,0 +1,200 @@
+// Copyright (c) 2016 Travis Watkins
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+use raw::RawQueuedRwLock;
+
+/// Same as `QueuedRwLock` but without poisoning, a panic while a guard is
+/// held just releases the lock.
+///
+/// With `()` as the data this is the lightest way to get the lock's
+/// ordering, nothing is kept or checked beyond the raw lock.
+pub struct QueuedRwLockNoPoison<T> {
+    inner: RawQueuedRwLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for QueuedRwLockNoPoison<T> {}
+unsafe impl<T: Send + Sync> Sync for QueuedRwLockNoPoison<T> {}
+
+impl<T> QueuedRwLockNoPoison<T> {
+    const_fn! {
+        pub const fn new(data: T) -> QueuedRwLockNoPoison<T> {
+            QueuedRwLockNoPoison {
+                inner: RawQueuedRwLock::new(),
+                data: UnsafeCell::new(data),
+            }
+        }
+    }
+
+    /// Same as `QueuedRwLock::read`, waiting behind queued writers.
+    pub fn read(&self) -> QueuedRwLockNoPoisonReadGuard<'_, T> {
+        self.inner.read();
+        QueuedRwLockNoPoisonReadGuard { lock: self }
+    }
+
+    /// Takes a read lock if that can be done without waiting, `None` if not.
+    pub fn try_read(&self) -> Option<QueuedRwLockNoPoisonReadGuard<'_, T>> {
+        if self.inner.try_read() {
+            Some(QueuedRwLockNoPoisonReadGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    /// Same as `QueuedRwLock::write`, waiting its turn in the queue.
+    pub fn write(&self) -> QueuedRwLockNoPoisonWriteGuard<'_, T> {
+        let ticket = self.inner.take_ticket();
+        self.inner.write(ticket);
+        QueuedRwLockNoPoisonWriteGuard { lock: self }
+    }
+
+    /// Takes a write lock if nobody holds the lock or is queued for it,
+    /// `None` if not. Like `QueuedRwLock::try_write` it doesn't take a place
+    /// in the queue, see `RawQueuedRwLock::try_write_skip_queue`.
+    pub fn try_write(&self) -> Option<QueuedRwLockNoPoisonWriteGuard<'_, T>> {
+        if self.inner.try_write_skip_queue() {
+            Some(QueuedRwLockNoPoisonWriteGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    /// Consumes the lock and returns the data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    /// The data, without locking since `&mut self` already rules out anyone
+    /// else holding the lock.
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for QueuedRwLockNoPoison<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => write!(f, "QueuedRwLockNoPoison {{ data: {:?} }}", &*guard),
+            None => write!(f, "QueuedRwLockNoPoison {{ <locked> }}"),
+        }
+    }
+}
+
+impl<T: Default> Default for QueuedRwLockNoPoison<T> {
+    fn default() -> QueuedRwLockNoPoison<T> {
+        QueuedRwLockNoPoison::new(T::default())
+    }
+}
+
+impl<T> From<T> for QueuedRwLockNoPoison<T> {
+    fn from(data: T) -> QueuedRwLockNoPoison<T> {
+        QueuedRwLockNoPoison::new(data)
+    }
+}
+
+#[must_use]
+pub struct QueuedRwLockNoPoisonReadGuard<'a, T: 'a> {
+    lock: &'a QueuedRwLockNoPoison<T>,
+}
+
+unsafe impl<'a, T: Sync> Sync for QueuedRwLockNoPoisonReadGuard<'a, T> {}
+
+impl<'a, T> Deref for QueuedRwLockNoPoisonReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for QueuedRwLockNoPoisonReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.inner.read_unlock();
+    }
+}
+
+#[must_use]
+pub struct QueuedRwLockNoPoisonWriteGuard<'a, T: 'a> {
+    lock: &'a QueuedRwLockNoPoison<T>,
+}
+
+unsafe impl<'a, T: Sync> Sync for QueuedRwLockNoPoisonWriteGuard<'a, T> {}
+
+impl<'a, T> Deref for QueuedRwLockNoPoisonWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for QueuedRwLockNoPoisonWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for QueuedRwLockNoPoisonWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.inner.write_unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use std::thread;
+
+    use raw::RawQueuedRwLock;
+    use {QueuedRwLockNoPoison, QueuedRwLockNoPoisonWriteGuard};
+
+    #[test]
+    fn smoke() {
+        let lock = QueuedRwLockNoPoison::new(0);
+        *lock.write() = 1;
+        assert_eq!(*lock.read(), 1);
+
+        let read_guard = lock.read();
+        assert!(lock.try_write().is_none());
+        assert!(lock.try_read().is_some());
+        drop(read_guard);
+
+        let write_guard = lock.try_write().unwrap();
+        assert!(lock.try_read().is_none());
+        drop(write_guard);
+
+        assert_eq!(lock.into_inner(), 1);
+    }
+
+    #[test]
+    fn zero_sized_is_free() {
+        assert_eq!(mem::size_of::<QueuedRwLockNoPoison<()>>(), mem::size_of::<RawQueuedRwLock>());
+        assert_eq!(mem::size_of::<QueuedRwLockNoPoisonWriteGuard<'_, ()>>(), mem::size_of::<usize>());
+    }
+
+    #[test]
+    fn panic_releases_lock() {
+        let lock = QueuedRwLockNoPoison::new(0);
+
+        let _ = thread::scope(|s| {
+            s.spawn(|| {
+                let mut guard = lock.write();
+                *guard = 1;
+                panic!();
+            }).join()
+        });
+
+        assert_eq!(*lock.read(), 1);
+        *lock.write() = 2;
+        assert_eq!(*lock.try_read().unwrap(), 2);
+    }
+}
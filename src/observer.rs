@@ -0,0 +1,65 @@
+// Copyright (c) 2016 Travis Watkins
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::time::Duration;
+
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+/// Gets told when a lock is taken and released, e.g. to record contention.
+/// Every method does nothing by default.
+///
+/// Methods are called on the thread doing the locking or unlocking, never
+/// while the lock's internal state is held, so they're free to take time or
+/// even use the lock. `wait` is how long the thread was held up, which is
+/// always zero for the async methods and without the `std` feature.
+///
+/// Upgradable reads count as reads, upgrading one releases the read and
+/// acquires a write under its ticket and downgrading does the opposite.
+///
+/// `ticket_dropped` is only called with the `debug_ticket_tracking` feature,
+/// when a ticket guard is dropped without being written or cancelled. The
+/// drop still takes and releases the lock in its turn, which usually means
+/// a ticket was taken by mistake.
+pub trait LockObserver: Send + Sync {
+    fn read_acquired(&self, _wait: Duration) {}
+    fn read_released(&self) {}
+    fn write_acquired(&self, _ticket: usize, _wait: Duration) {}
+    fn write_released(&self) {}
+    fn ticket_taken(&self, _ticket: usize) {}
+    fn ticket_dropped(&self, _ticket: usize) {}
+}
+
+// Only reads the clock when there's someone to tell
+#[cfg(feature = "std")]
+pub struct WaitTimer(Option<Instant>);
+
+#[cfg(feature = "std")]
+impl WaitTimer {
+    pub fn start(timed: bool) -> WaitTimer {
+        WaitTimer(if timed { Some(Instant::now()) } else { None })
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.0.map_or(Duration::ZERO, |start| start.elapsed())
+    }
+}
+
+#[cfg(not(feature = "std"))]
+pub struct WaitTimer;
+
+#[cfg(not(feature = "std"))]
+impl WaitTimer {
+    pub fn start(_timed: bool) -> WaitTimer {
+        WaitTimer
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Duration::ZERO
+    }
+}
@@ -0,0 +1,463 @@
+// Copyright (c) 2016 Travis Watkins
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Poisoning primitives in the shape of the poison crate, except the flag is
+// atomic so it can be checked without holding the lock that guards the data.
+
+use alloc::string::String;
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use std::fmt;
+#[cfg(feature = "std")]
+use std::error::Error;
+
+use raw::{ReadBlocked, WriteBlocked};
+use sync::Mutex;
+
+#[cfg(feature = "std")]
+pub use std::sync::{LockResult, PoisonError, TryLockError, TryLockResult};
+
+/// Why a lock was poisoned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PoisonCause {
+    context: Option<String>,
+    panicked: bool,
+}
+
+impl PoisonCause {
+    /// Whether a panic poisoned the lock, rather than a call to `poison`.
+    pub fn panicked(&self) -> bool {
+        self.panicked
+    }
+
+    /// What the guard was set up to say with `set_poison_context`, if
+    /// anything.
+    pub fn context(&self) -> Option<&str> {
+        self.context.as_deref()
+    }
+}
+
+/// What happens when a thread panics while holding a write lock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnPanic {
+    /// The lock is poisoned and other threads get to decide what to do
+    /// about it.
+    #[default]
+    Poison,
+    /// The process is aborted so nothing gets to see data that may have been
+    /// left half updated. Needs the `std` feature, without it there's no
+    /// telling a guard is dropped during a panic.
+    Abort,
+}
+
+pub struct Poison<T: ?Sized> {
+    flag: Flag,
+    data: UnsafeCell<T>,
+}
+
+// The cause is only looked at once the flag says there is one
+struct Flag {
+    poisoned: AtomicBool,
+    cause: Mutex<Option<PoisonCause>>,
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    on_panic: OnPanic,
+}
+
+impl Flag {
+    fn poison(&self, cause: PoisonCause) {
+        *self.cause.lock() = Some(cause);
+        self.poisoned.store(true, Ordering::Release);
+    }
+}
+
+impl<T> Poison<T> {
+    const_fn! {
+        pub const fn new(val: T) -> Poison<T> {
+            Poison::with_policy(val, OnPanic::Poison)
+        }
+    }
+
+    const_fn! {
+        pub const fn with_policy(val: T, on_panic: OnPanic) -> Poison<T> {
+            Poison {
+                flag: Flag {
+                    poisoned: AtomicBool::new(false),
+                    cause: Mutex::new(None),
+                    on_panic,
+                },
+                data: UnsafeCell::new(val),
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.is_poisoned();
+        let data = self.data.into_inner();
+
+        if poisoned {
+            Err(PoisonError::new(data))
+        } else {
+            Ok(data)
+        }
+    }
+}
+
+impl<T: ?Sized> Poison<T> {
+    pub fn is_poisoned(&self) -> bool {
+        self.flag.poisoned.load(Ordering::Acquire)
+    }
+
+    pub fn cause(&self) -> Option<PoisonCause> {
+        if self.is_poisoned() {
+            self.flag.cause.lock().clone()
+        } else {
+            None
+        }
+    }
+
+    pub fn poison(&self) {
+        self.flag.poison(PoisonCause {
+            context: None,
+            panicked: false,
+        });
+    }
+
+    // For carrying over the cause from another lock
+    pub fn poison_with(&self, cause: PoisonCause) {
+        self.flag.poison(cause);
+    }
+
+    pub fn heal(&self) {
+        let mut cause = self.flag.cause.lock();
+        self.flag.poisoned.store(false, Ordering::Release);
+        *cause = None;
+    }
+
+    pub fn data_ptr(&self) -> *mut T {
+        self.data.get()
+    }
+
+    // Caller must hold at least a shared lock on the data
+    pub unsafe fn get(&self) -> LockResult<&T> {
+        let data = &*self.data.get();
+
+        if self.is_poisoned() {
+            Err(PoisonError::new(data))
+        } else {
+            Ok(data)
+        }
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let poisoned = self.is_poisoned();
+        let data = unsafe { &mut *self.data.get() };
+
+        if poisoned {
+            Err(PoisonError::new(data))
+        } else {
+            Ok(data)
+        }
+    }
+
+    // Caller must hold an exclusive lock on the data for as long as the
+    // returned guard lives
+    pub unsafe fn lock(&self) -> LockResult<PoisonGuard<'_, T>> {
+        let guard = PoisonGuard {
+            data: &mut *self.data.get(),
+            guard: RawPoisonGuard {
+                flag: &self.flag,
+                panicking: panicking(),
+                forced: false,
+                disarmed: false,
+                context: None,
+            },
+        };
+
+        if self.is_poisoned() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+}
+
+/// If the current thread panics before this is dropped the `Poison` it came
+/// from is poisoned.
+pub struct PoisonGuard<'poison, T: ?Sized + 'poison> {
+    data: &'poison mut T,
+    guard: RawPoisonGuard<'poison>,
+}
+
+impl<'poison, T: ?Sized> PoisonGuard<'poison, T> {
+    pub fn get(&self) -> &T {
+        self.data
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data
+    }
+
+    // Shows up in the cause if this guard ends up poisoning the data
+    pub fn set_context(&mut self, context: String) {
+        self.guard.context = Some(context);
+    }
+
+    // The data is left alone if the thread panics, though `OnPanic::Abort`
+    // still aborts
+    pub fn disarm(&mut self) {
+        self.guard.disarmed = true;
+    }
+
+    // The data is poisoned when the guard is dropped, panic or not
+    pub fn poison_on_drop(&mut self) {
+        self.guard.forced = true;
+    }
+
+    // Hands out `data` in place of the original, which must point somewhere
+    // inside what this guard already covers
+    pub unsafe fn map<U: ?Sized>(self, data: *mut U) -> PoisonGuard<'poison, U> {
+        PoisonGuard {
+            data: &mut *data,
+            guard: self.guard,
+        }
+    }
+}
+
+struct RawPoisonGuard<'poison> {
+    flag: &'poison Flag,
+    panicking: bool,
+    forced: bool,
+    disarmed: bool,
+    context: Option<String>,
+}
+
+impl<'poison> Drop for RawPoisonGuard<'poison> {
+    fn drop(&mut self) {
+        let panicked = !self.panicking && panicking();
+        if self.forced || panicked {
+            #[cfg(feature = "std")]
+            if self.flag.on_panic == OnPanic::Abort && panicking() {
+                ::std::process::abort();
+            }
+        }
+
+        if self.forced || (panicked && !self.disarmed) {
+            self.flag.poison(PoisonCause {
+                context: self.context.take(),
+                panicked: panicking(),
+            });
+        }
+    }
+}
+
+/// Applies `f` to the value in either variant of a `LockResult`.
+pub fn map_result<T, U, F>(result: LockResult<T>, f: F) -> LockResult<U>
+    where F: FnOnce(T) -> U
+{
+    match result {
+        Ok(t) => Ok(f(t)),
+        Err(e) => Err(PoisonError::new(f(e.into_inner()))),
+    }
+}
+
+#[cfg(feature = "std")]
+fn panicking() -> bool {
+    ::std::thread::panicking()
+}
+
+// Without std there's no telling whether a guard is being dropped during a
+// panic, so nothing is ever poisoned
+#[cfg(not(feature = "std"))]
+fn panicking() -> bool {
+    false
+}
+
+/// Same as `std::sync::PoisonError`, for when std isn't available.
+#[cfg(not(feature = "std"))]
+pub struct PoisonError<T> {
+    guard: T,
+}
+
+#[cfg(not(feature = "std"))]
+impl<T> PoisonError<T> {
+    pub fn new(guard: T) -> PoisonError<T> {
+        PoisonError { guard }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.guard
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.guard
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<T> fmt::Debug for PoisonError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PoisonError").finish_non_exhaustive()
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<T> fmt::Display for PoisonError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        "poisoned lock: another task failed inside".fmt(f)
+    }
+}
+
+/// Same as `std::sync::TryLockError`, for when std isn't available.
+#[cfg(not(feature = "std"))]
+pub enum TryLockError<T> {
+    Poisoned(PoisonError<T>),
+    WouldBlock,
+}
+
+#[cfg(not(feature = "std"))]
+impl<T> From<PoisonError<T>> for TryLockError<T> {
+    fn from(err: PoisonError<T>) -> TryLockError<T> {
+        TryLockError::Poisoned(err)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<T> fmt::Debug for TryLockError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryLockError::Poisoned(ref err) => err.fmt(f),
+            TryLockError::WouldBlock => "WouldBlock".fmt(f),
+        }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<T> fmt::Display for TryLockError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryLockError::Poisoned(ref err) => err.fmt(f),
+            TryLockError::WouldBlock => "try_lock failed because the operation would block".fmt(f),
+        }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+pub type LockResult<G> = Result<G, PoisonError<G>>;
+
+#[cfg(not(feature = "std"))]
+pub type TryLockResult<G> = Result<G, TryLockError<G>>;
+
+/// Like `TryLockError` but says why a read lock couldn't be taken.
+pub enum TryReadError<T> {
+    /// A writer holds the lock, or is waiting for readers to leave and
+    /// keeping new ones out.
+    WriterActive,
+    /// No writer holds the lock but readers wait behind queued ones.
+    WriterQueued,
+    /// The reader limit has been reached.
+    MaxReaders,
+    Poisoned(PoisonError<T>),
+}
+
+impl<T> TryReadError<T> {
+    pub fn from_blocked(blocked: ReadBlocked) -> TryReadError<T> {
+        match blocked {
+            ReadBlocked::WriterActive => TryReadError::WriterActive,
+            ReadBlocked::WriterQueued => TryReadError::WriterQueued,
+            ReadBlocked::MaxReaders => TryReadError::MaxReaders,
+        }
+    }
+}
+
+impl<T> From<PoisonError<T>> for TryReadError<T> {
+    fn from(err: PoisonError<T>) -> TryReadError<T> {
+        TryReadError::Poisoned(err)
+    }
+}
+
+impl<T> fmt::Debug for TryReadError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryReadError::WriterActive => "WriterActive".fmt(f),
+            TryReadError::WriterQueued => "WriterQueued".fmt(f),
+            TryReadError::MaxReaders => "MaxReaders".fmt(f),
+            TryReadError::Poisoned(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl<T> fmt::Display for TryReadError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryReadError::WriterActive => "try_read failed because a writer holds the lock".fmt(f),
+            TryReadError::WriterQueued => "try_read failed because writers are queued".fmt(f),
+            TryReadError::MaxReaders => "try_read failed because the reader limit was reached".fmt(f),
+            TryReadError::Poisoned(ref err) => err.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> Error for TryReadError<T> {}
+
+/// Like `TryLockError` but says why a write lock couldn't be taken.
+pub enum TryWriteError<T> {
+    /// Readers hold the lock.
+    ReadersActive,
+    /// A writer holds the lock, or is waiting for readers to leave.
+    WriterActive,
+    /// Others are queued up and would be jumped.
+    QueueNonEmpty,
+    Poisoned(PoisonError<T>),
+}
+
+impl<T> TryWriteError<T> {
+    pub fn from_blocked(blocked: WriteBlocked) -> TryWriteError<T> {
+        match blocked {
+            WriteBlocked::ReadersActive => TryWriteError::ReadersActive,
+            WriteBlocked::WriterActive => TryWriteError::WriterActive,
+            WriteBlocked::QueueNonEmpty => TryWriteError::QueueNonEmpty,
+        }
+    }
+}
+
+impl<T> From<PoisonError<T>> for TryWriteError<T> {
+    fn from(err: PoisonError<T>) -> TryWriteError<T> {
+        TryWriteError::Poisoned(err)
+    }
+}
+
+impl<T> fmt::Debug for TryWriteError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryWriteError::ReadersActive => "ReadersActive".fmt(f),
+            TryWriteError::WriterActive => "WriterActive".fmt(f),
+            TryWriteError::QueueNonEmpty => "QueueNonEmpty".fmt(f),
+            TryWriteError::Poisoned(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl<T> fmt::Display for TryWriteError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryWriteError::ReadersActive => "try_write failed because readers hold the lock".fmt(f),
+            TryWriteError::WriterActive => "try_write failed because a writer holds the lock".fmt(f),
+            TryWriteError::QueueNonEmpty => "try_write failed because others are queued".fmt(f),
+            TryWriteError::Poisoned(ref err) => err.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> Error for TryWriteError<T> {}
@@ -6,128 +6,1429 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::sync::{Condvar, Mutex};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use std::ops::Deref;
+use std::time::Duration;
 
+#[cfg(feature = "std")]
+use std::thread::{self, ThreadId};
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+#[cfg(feature = "async")]
+use std::mem;
+#[cfg(feature = "async")]
+use std::task::{Context, Waker};
+
+#[cfg(all(feature = "lock_api", not(loom)))]
+use lock_api;
+
+use deadlock;
+use observer::{LockObserver, WaitTimer};
+#[cfg(feature = "stats")]
+use stats::{LockStats, Stats};
+use sync::{hint, Condvar, Mutex, MutexGuard};
+use sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/// Decides who goes first when readers and writers are both waiting.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FairnessMode {
+    /// Readers and writers are let in in the order they started waiting,
+    /// with readers that are next to each other going in together.
+    #[default]
+    Fifo,
+    /// Readers only wait for an active writer, queued writers wait for a
+    /// moment with no readers. Writers can starve.
+    ReaderPreferring,
+    /// Readers wait for every queued writer, even ones that queued up after
+    /// them. Readers can starve.
+    WriterPreferring,
+    /// Like `ReaderPreferring` but only this many readers can go ahead of
+    /// queued writers, after that new readers wait until a writer has had
+    /// the lock. Readers that overlap can't keep a writer out for good.
+    WriteRatio(usize),
+    /// Like `Fifo` but in turns, every writer that's queued when a reader
+    /// starts waiting goes first, then every reader that's waiting goes in
+    /// together before any writer that queued up after them.
+    Alternating,
+}
+
+/// Why `RawQueuedRwLock::try_read_reason` couldn't take a read lock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadBlocked {
+    /// A writer holds the lock or is waiting for readers to leave.
+    WriterActive,
+    /// Writers are queued and readers wait behind them.
+    WriterQueued,
+    /// The reader limit has been reached.
+    MaxReaders,
+}
+
+/// Why `RawQueuedRwLock::try_write_reason` couldn't take a write lock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteBlocked {
+    /// Readers, or an upgradable reader, hold the lock.
+    ReadersActive,
+    /// A writer holds the lock or is waiting for readers to leave.
+    WriterActive,
+    /// Writers, or readers with a place in the queue, are waiting their
+    /// turn and taking the lock would go ahead of them.
+    QueueNonEmpty,
+}
+
+/// How many writes a lock has seen, see `RawQueuedRwLock::write_counts`.
+/// Both wrap around on overflow.
+///
+/// A ticket that's never used for a write stays in `requested` without
+/// ever reaching `completed`, so the difference between them isn't the
+/// number of writes in flight.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WriteCounts {
+    /// Tickets handed out, including ones taken by upgradable reads and ones
+    /// later cancelled, plus priority writes.
+    pub requested: usize,
+    /// Write locks released, a downgrade counts as one.
+    pub completed: usize,
+}
+
+/// The lock behind `QueuedRwLock`, without any data attached.
+///
+/// Every unlock must match an earlier lock of the same kind and every ticket
+/// must be passed to exactly one of `write`, `write_until` or `cancel_ticket`,
+/// or to a `try_write` that succeeds. Anything else leaves the lock in a
+/// broken state.
+///
+/// Without the `std` feature waiting is done by spinning, and the timed
+/// methods aren't available.
 pub struct RawQueuedRwLock {
     state: Mutex<State>,
+    // Kept out of State so readers don't have to take the state lock, and on
+    // its own cache line so reader churn doesn't slow down access to the
+    // rest of the lock or the data next to it
+    readers: CachePadded<Readers>,
     reader: Condvar,
     writer: Condvar,
+    // Woken along with a bump of notifications, for wait_for_notify
+    changed: Condvar,
+    notifications: AtomicUsize,
+    // Whether letting go of a write lock counts as a notification
+    notify_on_write: AtomicBool,
+    // Writers waiting on their turn are spread over these by ticket so only
+    // the next in line, and whoever shares its slot, gets woken
+    tickets: [Condvar; TICKET_SLOTS],
+    // Bumped on every notify so spinning waiters can tell when to recheck
+    notified: AtomicUsize,
+    // Bumped when a write lock is taken and again when it's let go, so it's
+    // odd while the data may be changing
+    generation: AtomicU64,
+    // How many times a blocking lock spins before sleeping
+    spin_count: usize,
+    observer: Option<Arc<dyn LockObserver>>,
+    #[cfg(feature = "stats")]
+    stats: Stats,
+    #[cfg(test)]
+    wakeups: AtomicUsize,
+}
+
+const DEFAULT_SPIN_COUNT: usize = 100;
+
+const TICKET_SLOTS: usize = 16;
+
+// How much each new queue length counts towards the average, the same
+// weight TCP gives each round trip time sample
+#[cfg(feature = "stats")]
+const QUEUE_DEPTH_WEIGHT: f64 = 0.125;
+
+#[cfg(not(loom))]
+const fn ticket_slots() -> [Condvar; TICKET_SLOTS] {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const SLOT: Condvar = Condvar::new();
+    [SLOT; TICKET_SLOTS]
+}
+
+#[cfg(loom)]
+fn ticket_slots() -> [Condvar; TICKET_SLOTS] {
+    ::std::array::from_fn(|_| Condvar::new())
 }
 
 impl RawQueuedRwLock {
-    pub fn new() -> RawQueuedRwLock {
+    const_fn! {
+        pub const fn new() -> RawQueuedRwLock {
+            RawQueuedRwLock::with_config(!SLOW, DEFAULT_SPIN_COUNT, FairnessMode::Fifo)
+        }
+    }
+
+    // At most max readers hold the lock at once, anyone else waits for one
+    // of them to leave
+    const_fn! {
+        pub const fn with_max_readers(max: usize) -> RawQueuedRwLock {
+            RawQueuedRwLock::with_config(max, DEFAULT_SPIN_COUNT, FairnessMode::Fifo)
+        }
+    }
+
+    // Blocking locks spin up to spin_count times before going to sleep, 0
+    // means they go straight to sleep
+    const_fn! {
+        pub const fn with_spin_count(spin_count: usize) -> RawQueuedRwLock {
+            RawQueuedRwLock::with_config(!SLOW, spin_count, FairnessMode::Fifo)
+        }
+    }
+
+    const_fn! {
+        pub const fn with_fairness(fairness: FairnessMode) -> RawQueuedRwLock {
+            RawQueuedRwLock::with_config(!SLOW, DEFAULT_SPIN_COUNT, fairness)
+        }
+    }
+
+    // Everything taken, released or queued for is reported to observer
+    pub fn with_observer(observer: Arc<dyn LockObserver>) -> RawQueuedRwLock {
         RawQueuedRwLock {
-            state: Mutex::new(State::new()),
-            reader: Condvar::new(),
-            writer: Condvar::new(),
+            observer: Some(observer),
+            ..RawQueuedRwLock::new()
+        }
+    }
+
+    // Everything QueuedRwLockBuilder can set, None for the default
+    pub(crate) fn configured(max_readers: Option<usize>,
+                             spin_count: Option<usize>,
+                             fairness: FairnessMode,
+                             observer: Option<Arc<dyn LockObserver>>)
+                             -> RawQueuedRwLock {
+        RawQueuedRwLock {
+            observer,
+            ..RawQueuedRwLock::with_config(max_readers.unwrap_or(!SLOW),
+                                           spin_count.unwrap_or(DEFAULT_SPIN_COUNT),
+                                           fairness)
+        }
+    }
+
+    const_fn! {
+        const fn with_config(max: usize, spin_count: usize, fairness: FairnessMode) -> RawQueuedRwLock {
+            assert!(max > 0, "max_readers must be at least 1");
+
+            RawQueuedRwLock {
+                state: Mutex::new(State::new(fairness)),
+                readers: CachePadded(Readers::new(max)),
+                reader: Condvar::new(),
+                writer: Condvar::new(),
+                changed: Condvar::new(),
+                notifications: AtomicUsize::new(0),
+                notify_on_write: AtomicBool::new(true),
+                tickets: ticket_slots(),
+                notified: AtomicUsize::new(0),
+                generation: AtomicU64::new(0),
+                spin_count,
+                observer: None,
+                #[cfg(feature = "stats")]
+                stats: Stats::new(),
+                #[cfg(test)]
+                wakeups: AtomicUsize::new(0),
+            }
         }
     }
 
     pub fn read(&self) {
-        let mut state = self.state.lock().unwrap();
+        self.read_contended();
+    }
+
+    // Same as read, returns whether it had to sleep waiting for the lock
+    pub fn read_contended(&self) -> bool {
+        let timer = self.start_wait();
+
+        if self.readers.try_add() {
+            deadlock::read_acquired(self);
+            self.observe(|observer| observer.read_acquired(timer.elapsed()));
+            return false;
+        }
+
+        let mut state = self.state.lock();
+        let blocked = !self.can_read(&state, None);
+        let mut parked = false;
+
+        // The writer we'd wait on is waiting for our own read to go
+        if blocked && (state.has_writer() || state.blocks_readers()) && deadlock::holds_read(self) {
+            drop(state);
+            panic!("read would deadlock: this thread already holds a read lock that a waiting \
+                    writer needs released, use read_recursive for nested reads");
+        }
 
-        while state.has_writer() {
-            state = self.writer.wait(state).unwrap();
+        if blocked {
+            let place = state.wait_to_read();
+            state.parked_readers += 1;
+            state = self.wait_while_parked(&self.writer, state, |state| {
+                state.admitted_readers == 0 && !self.can_read(state, place)
+            }, &mut parked);
+            state.parked_readers -= 1;
+            self.stop_waiting(&mut state, place);
         }
 
-        state.add_reader();
+        // Already counted by write_unlock if it let us in while parked
+        if blocked && state.admitted_readers != 0 {
+            state.admitted_readers -= 1;
+        } else {
+            self.add_reader(&mut state);
+        }
+
+        drop(state);
+        deadlock::read_acquired(self);
+        self.observe(|observer| observer.read_acquired(timer.elapsed()));
+        parked
+    }
+
+    // Returns false if the deadline passed before the lock could be taken
+    #[cfg(feature = "std")]
+    pub fn read_until(&self, deadline: Instant) -> bool {
+        let timer = self.start_wait();
+
+        if self.readers.try_add() {
+            deadlock::read_acquired(self);
+            self.observe(|observer| observer.read_acquired(timer.elapsed()));
+            return true;
+        }
+
+        let mut state = self.state.lock();
+        let place = if !self.can_read(&state, None) {
+            state.wait_to_read()
+        } else {
+            None
+        };
+
+        while !self.can_read(&state, place) {
+            let now = Instant::now();
+            if now >= deadline {
+                self.stop_waiting(&mut state, place);
+                return false;
+            }
+            state = self.writer.wait_timeout(state, deadline - now);
+        }
+
+        self.stop_waiting(&mut state, place);
+        self.add_reader(&mut state);
+
+        drop(state);
+        deadlock::read_acquired(self);
+        self.observe(|observer| observer.read_acquired(timer.elapsed()));
+        true
     }
 
     pub fn try_read(&self) -> bool {
-        let mut state = self.state.lock().unwrap();
+        self.try_read_reason().is_ok()
+    }
 
-        if !state.has_writer() {
-            state.add_reader();
-            true
+    // Like try_read but says what got in the way. A writer still waiting for
+    // readers to leave counts as active since it already keeps readers out
+    pub fn try_read_reason(&self) -> Result<(), ReadBlocked> {
+        let timer = self.start_wait();
+
+        if self.readers.try_add() {
+            deadlock::read_acquired(self);
+            self.observe(|observer| observer.read_acquired(timer.elapsed()));
+            return Ok(());
+        }
+
+        let mut state = self.state.lock();
+
+        if self.can_read(&state, None) {
+            self.add_reader(&mut state);
+            drop(state);
+            deadlock::read_acquired(self);
+            self.observe(|observer| observer.read_acquired(timer.elapsed()));
+            Ok(())
+        } else if state.has_writer() {
+            Err(ReadBlocked::WriterActive)
+        } else if state.blocks_readers() {
+            Err(ReadBlocked::WriterQueued)
         } else {
-            false
+            Err(ReadBlocked::MaxReaders)
+        }
+    }
+
+    // Like read but goes ahead regardless of writers as long as someone else
+    // already has a read lock, which is what keeps nested reads from
+    // deadlocking against a queued writer
+    pub fn read_recursive(&self) {
+        let timer = self.start_wait();
+
+        if !self.readers.try_add() {
+            let state = self.state.lock();
+            let mut state = self.wait_while(&self.writer, state, |state| {
+                (state.blocks_readers() && !self.has_readers()) || self.readers.full()
+            });
+            self.add_reader(&mut state);
+        }
+
+        deadlock::read_acquired(self);
+        self.observe(|observer| observer.read_acquired(timer.elapsed()));
+    }
+
+    // Like read but only waits for a writer that holds the lock or is
+    // waiting on readers to leave, whatever the fairness mode
+    pub fn read_impatient(&self) {
+        let timer = self.start_wait();
+
+        if !self.readers.try_add() {
+            let state = self.state.lock();
+            let mut state = self.wait_while(&self.writer, state, |state| {
+                state.has_writer() || self.readers.full()
+            });
+            self.add_reader(&mut state);
         }
+
+        deadlock::read_acquired(self);
+        self.observe(|observer| observer.read_acquired(timer.elapsed()));
     }
 
     pub fn read_unlock(&self) {
-        let mut state = self.state.lock().unwrap();
-        state.remove_reader();
+        // Only the last reader out has a writer to wake, and only if the
+        // writer has turned the fast path off. Otherwise there's only someone
+        // to wake if we were taking up the last free place.
+        //
+        // A writer turns the fast path off before it looks at the count, both
+        // under the state lock, so either it sees us gone or we see the slow
+        // bit and can't get the state lock until it's waiting
+        let readers = self.readers.remove();
+        deadlock::read_released(self);
+        self.observe(|observer| observer.read_released());
+
+        let last = readers == SLOW | 1;
+        let was_full = readers & !SLOW == self.readers.max;
+
+        if !last && !was_full {
+            return;
+        }
+
+        let mut state = self.state.lock();
 
         if state.has_writer() {
-            if !state.has_readers() {
+            if !self.has_readers() {
+                state.wake_drain();
+                self.notified.fetch_add(1, Ordering::Release);
                 self.reader.notify_all();
             }
+        } else if was_full ||
+                  ((state.prefers_readers() || state.idle_waiters != 0) &&
+                   !self.has_readers()) {
+            // Queued writers may be waiting on a reader that was waiting on
+            // us, or for a moment with no readers, as may wait_idle
+            self.notify_writer(&mut state);
         }
     }
 
-    // Calls to take_ticket MUST eventually call write or else they will
-    // deadlock all future callers
+    // Calls to take_ticket MUST eventually call write or write_until or else
+    // they will deadlock all future callers
     pub fn take_ticket(&self) -> usize {
-        let mut state = self.state.lock().unwrap();
-        state.take_ticket()
+        let mut state = self.state.lock();
+        let ticket = state.take_ticket();
+        self.update_fast_path(&state);
+        #[cfg(feature = "stats")]
+        self.stats.queued(state.queue_len());
+
+        drop(state);
+        self.observe(|observer| observer.ticket_taken(ticket));
+        ticket
     }
 
     pub fn write(&self, ticket: usize) {
-        let mut state = self.state.lock().unwrap();
+        self.write_contended(ticket);
+    }
+
+    // Same as write, returns whether it had to sleep waiting for its turn or
+    // for readers to leave
+    pub fn write_contended(&self, ticket: usize) -> bool {
+        let timer = self.start_wait();
+        let mut parked = false;
+        let state = self.dequeue(ticket, &mut parked);
+
+        drop(self.wait_while_parked(&self.reader, state, |_| self.has_readers(), &mut parked));
+        self.begin_write();
+        self.observe(|observer| observer.write_acquired(ticket, timer.elapsed()));
+        parked
+    }
+
+    // The first half of write, returns once it's our turn and new readers
+    // are kept out. Must be followed by write_drain, write_drain_until or
+    // write_abandon
+    pub fn write_dequeue(&self, ticket: usize) {
+        drop(self.dequeue(ticket, &mut false));
+    }
+
+    // The second half of write, waits for readers to leave
+    pub fn write_drain(&self, ticket: usize) {
+        let timer = self.start_wait();
+        let state = self.state.lock();
+
+        drop(self.wait_while(&self.reader, state, |_| self.has_readers()));
+        self.begin_write();
+        self.observe(|observer| observer.write_acquired(ticket, timer.elapsed()));
+    }
 
-        while state.has_writer() && !state.is_next(ticket) {
-            state = self.writer.wait(state).unwrap();
+    // Returns false if the deadline passed before readers left, in which case
+    // the write has been abandoned
+    #[cfg(feature = "std")]
+    pub fn write_drain_until(&self, ticket: usize, deadline: Instant) -> bool {
+        let timer = self.start_wait();
+        let state = self.state.lock();
+
+        if self.drain_until(state, deadline) {
+            self.begin_write();
+            self.observe(|observer| observer.write_acquired(ticket, timer.elapsed()));
+            true
+        } else {
+            false
+        }
+    }
+
+    // Gives up after write_dequeue without ever having had the lock
+    pub fn write_abandon(&self) {
+        let mut state = self.state.lock();
+        self.abandon(&mut state);
+    }
+
+    // Gives up a ticket without ever taking the lock, if it isn't next yet it's
+    // skipped once it would have been
+    pub fn cancel_ticket(&self, ticket: usize) {
+        let mut state = self.state.lock();
+        state.cancel_ticket(ticket);
+        self.update_fast_path(&state);
+        self.notify_writer(&mut state);
+    }
+
+    // Returns false if the deadline passed before the lock could be taken, in
+    // which case the ticket has been retired and must not be used again
+    #[cfg(feature = "std")]
+    pub fn write_until(&self, ticket: usize, deadline: Instant) -> bool {
+        let timer = self.start_wait();
+        let mut state = self.state.lock();
+
+        while self.blocks_writer(&state, ticket) {
+            let now = Instant::now();
+            if now >= deadline {
+                state.cancel_ticket(ticket);
+                self.update_fast_path(&state);
+                self.notify_writer(&mut state);
+                return false;
+            }
+            state = self.ticket_slot(ticket).wait_timeout(state, deadline - now);
         }
 
         state.add_writer();
+        self.update_fast_path(&state);
+
+        if self.drain_until(state, deadline) {
+            self.begin_write();
+            self.observe(|observer| observer.write_acquired(ticket, timer.elapsed()));
+            true
+        } else {
+            false
+        }
+    }
 
-        while state.has_readers() {
-            state = self.reader.wait(state).unwrap();
+    // Only succeeds if it's the ticket's turn and there are no readers,
+    // otherwise the ticket is still ours to use. Readers are already on the
+    // slow path while the ticket is queued so the count can be trusted
+    pub fn try_write(&self, ticket: usize) -> bool {
+        let mut state = self.state.lock();
+
+        if self.blocks_writer(&state, ticket) || self.has_readers() {
+            return false;
         }
+
+        state.add_writer();
+        self.update_fast_path(&state);
+
+        drop(state);
+        self.begin_write();
+        self.observe(|observer| observer.write_acquired(ticket, Duration::ZERO));
+        true
     }
 
     // Only succeeds if there are no pending writes
     pub fn try_write_skip_queue(&self) -> bool {
-        let mut state = self.state.lock().unwrap();
+        self.try_write_reason().is_ok()
+    }
+
+    // Like try_write_skip_queue but says what got in the way, or returns
+    // the ticket it used
+    pub fn try_write_reason(&self) -> Result<usize, WriteBlocked> {
+        let timer = self.start_wait();
+        let mut state = self.state.lock();
 
-        if !state.has_writer() && !state.has_readers() && state.queue_empty() {
-            state.take_ticket();
+        if state.has_writer() {
+            return Err(WriteBlocked::WriterActive);
+        } else if state.has_upgradable() {
+            return Err(WriteBlocked::ReadersActive);
+        } else if state.blocks_writer(state.total_tickets) {
+            return Err(WriteBlocked::QueueNonEmpty);
+        }
+
+        // Readers have to be shut out before we can trust the count
+        self.readers.set_slow(true);
+
+        if !self.has_readers() {
+            let ticket = state.take_ticket();
             state.add_writer();
-            true
+
+            drop(state);
+            self.begin_write();
+            self.observe(|observer| {
+                observer.ticket_taken(ticket);
+                observer.write_acquired(ticket, timer.elapsed());
+            });
+            Ok(ticket)
         } else {
-            false
+            self.update_fast_path(&state);
+            Err(WriteBlocked::ReadersActive)
         }
     }
 
+    // Waits in the writer queue like write does, but only takes a read lock
+    // once it's our turn, holding off later writers until upgrade or
+    // upgradable_unlock. Returns the ticket it used
+    pub fn upgradable_read(&self) -> usize {
+        let timer = self.start_wait();
+        let ticket = self.take_ticket();
+
+        let state = self.state.lock();
+        let mut state = self.wait_while(self.ticket_slot(ticket), state, |state| state.blocks_writer(ticket));
+
+        // Readers held back by our ticket can come in now
+        state.add_upgradable(ticket);
+        self.update_fast_path(&state);
+        self.notify_writer(&mut state);
+
+        drop(state);
+        self.observe(|observer| observer.read_acquired(timer.elapsed()));
+        ticket
+    }
+
+    pub fn upgradable_unlock(&self) {
+        let mut state = self.state.lock();
+        state.remove_upgradable();
+        self.notify_writer(&mut state);
+
+        drop(state);
+        self.observe(|observer| observer.read_released());
+    }
+
+    pub fn upgrade(&self) {
+        let timer = self.start_wait();
+        let mut state = self.state.lock();
+        let ticket = state.upgrade();
+        self.update_fast_path(&state);
+
+        drop(self.wait_while(&self.reader, state, |_| self.has_readers()));
+        self.begin_write();
+        self.observe(|observer| {
+            observer.read_released();
+            observer.write_acquired(ticket, timer.elapsed());
+        });
+    }
+
+    // Same as upgrade but gives up rather than waiting on readers
+    pub fn try_upgrade(&self) -> bool {
+        let timer = self.start_wait();
+        let mut state = self.state.lock();
+
+        // Readers have to be shut out before we can trust the count
+        self.readers.set_slow(true);
+
+        let ticket = if !self.has_readers() {
+            Some(state.upgrade())
+        } else {
+            None
+        };
+
+        self.update_fast_path(&state);
+        drop(state);
+
+        if let Some(ticket) = ticket {
+            self.begin_write();
+            self.observe(|observer| {
+                observer.read_released();
+                observer.write_acquired(ticket, timer.elapsed());
+            });
+        }
+        ticket.is_some()
+    }
+
+    // Swaps the write lock for a single read lock without letting anyone
+    // else in between
+    pub fn downgrade(&self) {
+        let mut state = self.state.lock();
+        self.end_write();
+        state.remove_writer();
+        self.readers.add();
+        self.update_fast_path(&state);
+        self.notify_writer(&mut state);
+
+        drop(state);
+        deadlock::read_acquired(self);
+        self.observe(|observer| {
+            observer.write_released();
+            observer.read_acquired(Duration::ZERO);
+        });
+    }
+
+    // Swaps the write lock for an upgradable read without letting anyone
+    // else in between. Readers can come back in, queued writers stay behind
+    // it the same as behind upgradable_read, which keeps the ticket the write
+    // was taken with
+    pub fn downgrade_to_upgradable(&self, ticket: usize) {
+        let mut state = self.state.lock();
+        self.end_write();
+        state.remove_writer();
+
+        // The write already used up a ticket, which the upgradable read
+        // carries on with rather than taking another
+        state.upgradable = Some(ticket);
+        self.update_fast_path(&state);
+        self.notify_writer(&mut state);
+
+        drop(state);
+        self.observe(|observer| {
+            observer.write_released();
+            observer.read_acquired(Duration::ZERO);
+        });
+    }
+
     pub fn write_unlock(&self) {
-        let mut state = self.state.lock().unwrap();
+        let mut state = self.state.lock();
+        self.end_write();
         state.remove_writer();
+
+        // With no writer behind us nobody parked in read can be jumping the
+        // queue, so let them all in now rather than each relocking the state
+        // to add itself
+        if state.queue_empty() {
+            self.admit_parked_readers(&mut state);
+        }
+
+        self.update_fast_path(&state);
+        self.notify_writer(&mut state);
+
+        drop(state);
+        self.observe(|observer| observer.write_released());
+    }
+
+    // Blocks until there's been a notification since notifications returned
+    // seen, or straight away if there has already
+    pub fn wait_for_notify(&self, seen: usize) {
+        let state = self.state.lock();
+        drop(self.wait_while(&self.changed, state, |_| self.notifications() == seen));
+    }
+
+    pub fn notifications(&self) -> usize {
+        self.notifications.load(Ordering::Relaxed)
+    }
+
+    // Wakes everyone in wait_for_notify
+    pub fn notify_condition(&self) {
+        let _state = self.state.lock();
+        self.notify();
+        self.notified.fetch_add(1, Ordering::Release);
+    }
+
+    // Turns off the notification that otherwise comes with every write
+    // unlock, leaving it to notify_condition
+    pub fn set_notify_on_write(&self, notify: bool) {
+        self.notify_on_write.store(notify, Ordering::Relaxed);
+    }
+
+    // Like write but without a ticket, waits only for whoever holds the lock
+    // now and then goes ahead of every queued ticket. Priority writers go in
+    // whatever order they wake up in. Reported under the ticket it went
+    // ahead of, which is returned, and it's unlocked with write_unlock the
+    // same as any writer
+    pub fn write_priority(&self) -> usize {
+        let timer = self.start_wait();
+        let mut state = self.state.lock();
+        state.priority_writers += 1;
+        self.update_fast_path(&state);
+
+        let mut state = self.wait_while(&self.writer, state, |state| {
+            state.has_writer() || state.has_upgradable()
+        });
+        state.add_priority_writer();
+        let ticket = state.next_ticket;
+        self.update_fast_path(&state);
+
+        drop(self.wait_while(&self.reader, state, |_| self.has_readers()));
+        self.begin_write();
+        self.observe(|observer| observer.write_acquired(ticket, timer.elapsed()));
+        ticket
+    }
+
+    // Blocks until nobody holds the lock or is queued for it, without taking
+    // it. Anything that can leave the lock idle goes through notify_writer
+    pub fn wait_idle(&self) {
+        let mut state = self.state.lock();
+        state.idle_waiters += 1;
+        self.update_fast_path(&state);
+
+        let mut state = self.wait_while(&self.writer, state, |state| {
+            state.has_writer() || state.has_upgradable() || !state.queue_empty() || self.has_readers()
+        });
+
+        state.idle_waiters -= 1;
+        self.update_fast_path(&state);
+    }
+
+    // Like write with a ticket of its own, except a thread that already
+    // holds the lock this way goes a level deeper instead of queueing behind
+    // itself. Each call must be matched by a write_reentrant_unlock on the
+    // same thread, the lock is only let go by the last one
+    #[cfg(feature = "std")]
+    pub fn write_reentrant(&self) {
+        let me = thread::current().id();
+
+        // Only we can have made ourselves the owner, so if we are we still
+        // hold the lock
+        let mut state = self.state.lock();
+        if let Some((owner, ref mut depth)) = state.owner {
+            if owner == me {
+                *depth += 1;
+                return;
+            }
+        }
+        drop(state);
+
+        let ticket = self.take_ticket();
+        self.write(ticket);
+        self.state.lock().owner = Some((me, 1));
+    }
+
+    #[cfg(feature = "std")]
+    pub fn write_reentrant_unlock(&self) {
+        let mut state = self.state.lock();
+        let depth = match state.owner {
+            Some((_, ref mut depth)) => {
+                *depth -= 1;
+                *depth
+            }
+            None => panic!("write_reentrant_unlock called without the lock held"),
+        };
+
+        if depth == 0 {
+            state.owner = None;
+            drop(state);
+            self.write_unlock();
+        }
+    }
+
+    // Returns true once the read lock is taken, otherwise the task is woken
+    // when it's worth trying again
+    #[cfg(feature = "async")]
+    pub(crate) fn poll_read(&self,
+                            key: &mut Option<usize>,
+                            place: &mut Option<usize>,
+                            cx: &mut Context<'_>)
+                            -> bool {
+        let mut state = self.state.lock();
+
+        if self.can_read(&state, *place) {
+            if let Some(key) = key.take() {
+                state.read_wakers.remove(&key);
+            }
+            self.stop_waiting(&mut state, place.take());
+            self.add_reader(&mut state);
+
+            drop(state);
+            self.observe(|observer| observer.read_acquired(Duration::ZERO));
+            true
+        } else {
+            if key.is_none() {
+                *place = state.wait_to_read();
+            }
+            let key = *key.get_or_insert_with(|| state.take_waker_key());
+            state.read_wakers.insert(key, cx.waker().clone());
+            false
+        }
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn forget_read(&self, key: usize, place: Option<usize>) {
+        let mut state = self.state.lock();
+        state.read_wakers.remove(&key);
+        self.stop_waiting(&mut state, place);
+    }
+
+    // Same as write but never blocks, admitted tracks whether our ticket has
+    // been used up and we're only waiting on readers
+    #[cfg(feature = "async")]
+    pub(crate) fn poll_write(&self, ticket: usize, admitted: &mut bool, cx: &mut Context<'_>) -> bool {
+        let mut state = self.state.lock();
+
+        if !*admitted {
+            if self.blocks_writer(&state, ticket) {
+                state.write_wakers.insert(ticket, cx.waker().clone());
+                return false;
+            }
+
+            state.write_wakers.remove(&ticket);
+            state.add_writer();
+            self.update_fast_path(&state);
+            *admitted = true;
+        }
+
+        if self.has_readers() {
+            state.drain_waker = Some(cx.waker().clone());
+            false
+        } else {
+            state.drain_waker = None;
+
+            drop(state);
+            self.begin_write();
+            self.observe(|observer| observer.write_acquired(ticket, Duration::ZERO));
+            true
+        }
+    }
+
+    // Backs out of a poll_write that never finished
+    #[cfg(feature = "async")]
+    pub(crate) fn forget_write(&self, ticket: usize, admitted: bool) {
+        let mut state = self.state.lock();
+
+        if admitted {
+            state.drain_waker = None;
+            state.remove_writer();
+            self.update_fast_path(&state);
+        } else {
+            state.write_wakers.remove(&ticket);
+            state.cancel_ticket(ticket);
+            self.update_fast_path(&state);
+        }
+
+        self.notify_writer(&mut state);
+    }
+
+    // Waits on condvar for as long as cond holds, but spins for a while first
+    // in case it's about to stop holding and we can skip going to sleep
+    fn wait_while<'a, F>(&'a self,
+                         condvar: &Condvar,
+                         state: MutexGuard<'a, State>,
+                         cond: F)
+                         -> MutexGuard<'a, State>
+        where F: FnMut(&State) -> bool
+    {
+        self.wait_while_parked(condvar, state, cond, &mut false)
+    }
+
+    // Same as wait_while, sets parked if it had to wait on the condvar
+    fn wait_while_parked<'a, F>(&'a self,
+                                condvar: &Condvar,
+                                mut state: MutexGuard<'a, State>,
+                                mut cond: F,
+                                parked: &mut bool)
+                                -> MutexGuard<'a, State>
+        where F: FnMut(&State) -> bool
+    {
+        let mut spins = 0;
+
+        while cond(&state) {
+            if spins < self.spin_count {
+                // Nothing we're waiting on changes without a notify so watch
+                // for one rather than fighting over the state lock
+                let notified = self.notified.load(Ordering::Relaxed);
+                drop(state);
+
+                while spins < self.spin_count && self.notified.load(Ordering::Acquire) == notified {
+                    spins += 1;
+                    hint::spin_loop();
+                }
+
+                state = self.state.lock();
+            } else {
+                state = condvar.wait(state);
+                *parked = true;
+
+                #[cfg(test)]
+                self.wakeups.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        state
+    }
+
+    // Wakes readers waiting for a writer to leave and whoever holds the next
+    // ticket, nobody else in the queue can have anything to wake up for
+    fn notify_writer(&self, state: &mut State) {
+        state.wake_waiters();
+        self.notified.fetch_add(1, Ordering::Release);
         self.writer.notify_all();
+        self.ticket_slot(state.next_ticket).notify_all();
+    }
+
+    fn dequeue(&self, ticket: usize, parked: &mut bool) -> MutexGuard<'_, State> {
+        let state = self.state.lock();
+        let mut state = self.wait_while_parked(self.ticket_slot(ticket), state, |state| {
+            self.blocks_writer(state, ticket)
+        }, parked);
+
+        state.add_writer();
+        self.update_fast_path(&state);
+        state
+    }
+
+    // Waits with the writer flag set for readers to leave, abandoning the
+    // write if they haven't by the deadline
+    #[cfg(feature = "std")]
+    fn drain_until(&self, mut state: MutexGuard<'_, State>, deadline: Instant) -> bool {
+        while self.has_readers() {
+            let now = Instant::now();
+            if now >= deadline {
+                self.abandon(&mut state);
+                return false;
+            }
+            state = self.reader.wait_timeout(state, deadline - now);
+        }
+
+        true
+    }
+
+    // The ticket was used up by add_writer so only the writer flag needs
+    // undoing
+    fn abandon(&self, state: &mut State) {
+        state.remove_writer();
+        self.update_fast_path(state);
+        self.notify_writer(state);
+    }
+
+    // Called once a write lock is held, before it's handed out. The fence
+    // keeps the writes to the data after the bump for optimistic readers
+    fn begin_write(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        atomic::fence(Ordering::Release);
+    }
+
+    // Always called with the state lock held, which notify needs
+    fn end_write(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+
+        if self.notify_on_write.load(Ordering::Relaxed) {
+            self.notify();
+        }
+    }
+
+    // Must be called with the state lock held so wait_for_notify can't miss
+    // the bump
+    fn notify(&self) {
+        self.notifications.fetch_add(1, Ordering::Relaxed);
+        self.changed.notify_all();
+    }
+
+    fn start_wait(&self) -> WaitTimer {
+        WaitTimer::start(self.observer.is_some() || cfg!(feature = "stats"))
+    }
+
+    fn observe<F: Fn(&dyn LockObserver)>(&self, f: F) {
+        #[cfg(feature = "stats")]
+        f(&self.stats);
+
+        if let Some(ref observer) = self.observer {
+            f(&**observer);
+        }
+    }
+
+    // Any reader let in with the state lock held comes through here so the
+    // ones going ahead of queued writers are counted
+    fn add_reader(&self, state: &mut State) {
+        if !state.queue_empty() {
+            state.jumped_readers += 1;
+        }
+        self.readers.add();
+    }
+
+    fn admit_parked_readers(&self, state: &mut State) {
+        let room = self.readers.max - self.readers.count();
+        let admitted = (state.parked_readers - state.admitted_readers).min(room);
+        self.readers.add_many(admitted);
+        state.admitted_readers += admitted;
+    }
+
+    fn ticket_slot(&self, ticket: usize) -> &Condvar {
+        &self.tickets[ticket % TICKET_SLOTS]
+    }
+
+    // Must be called with the state lock held any time has_writer or the
+    // queue could have changed. Readers take the slow path whenever there's
+    // a writer around, even if they don't have to wait for it, so the last
+    // one out knows to wake it
+    fn update_fast_path(&self, state: &State) {
+        self.readers.set_slow(state.has_writer() || !state.queue_empty() || state.idle_waiters != 0);
+    }
+
+    // A reader with a place in the queue only waits on writers ahead of it,
+    // anyone else waits the same as a new reader
+    fn can_read(&self, state: &State, place: Option<usize>) -> bool {
+        let blocked = match place {
+            Some(place) => state.blocks_reader_at(place),
+            None => state.blocks_readers(),
+        };
+
+        !blocked && !self.readers.full()
+    }
+
+    // When readers are preferred writers wait for them to be gone before
+    // going ahead rather than keeping new ones out while they leave
+    fn blocks_writer(&self, state: &State, ticket: usize) -> bool {
+        state.blocks_writer(ticket) ||
+        (state.prefers_readers() && self.has_readers())
+    }
+
+    // Writers queued behind a reader's place may be waiting on it to leave
+    fn stop_waiting(&self, state: &mut State, place: Option<usize>) {
+        if let Some(place) = place {
+            state.stop_waiting(place);
+            self.notify_writer(state);
+        }
+    }
+
+    fn has_readers(&self) -> bool {
+        self.readers.count() != 0
+    }
+
+    pub fn reader_count(&self) -> usize {
+        let state = self.state.lock();
+        self.readers.count() + state.has_upgradable() as usize
+    }
+
+    pub fn has_writer(&self) -> bool {
+        let state = self.state.lock();
+        state.has_writer()
+    }
+
+    pub fn queue_len(&self) -> usize {
+        let state = self.state.lock();
+        state.queue_len()
+    }
+
+    // Readers blocked in read, for tests to wait until one has queued up
+    #[cfg(test)]
+    pub fn parked_readers(&self) -> usize {
+        let state = self.state.lock();
+        state.parked_readers
+    }
+
+    // Whether a write taking a ticket now would have to wait for anyone
+    pub fn write_would_block(&self) -> bool {
+        let state = self.state.lock();
+        self.blocks_writer(&state, state.total_tickets) || self.has_readers()
+    }
+
+    // Totals so far, for working out throughput by sampling. Completed
+    // writes come from the generation, which goes up twice for each
+    pub fn write_counts(&self) -> WriteCounts {
+        let state = self.state.lock();
+        let requested = state.total_tickets.wrapping_add(state.priority_writes);
+        WriteCounts {
+            requested,
+            completed: (self.generation() >> 1) as usize,
+        }
+    }
+
+    // Goes up by one when a write lock is taken and by one when it's let go,
+    // wrapping on overflow
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    // Starts a read without taking the lock, returns the generation to pass
+    // to optimistic_validate or None if a writer has the lock. Anything read
+    // in between may be torn and must be thrown away unless validate says
+    // otherwise
+    pub fn optimistic_begin(&self) -> Option<u64> {
+        let generation = self.generation.load(Ordering::Acquire);
+
+        if generation & 1 == 0 {
+            Some(generation)
+        } else {
+            None
+        }
+    }
+
+    pub fn optimistic_validate(&self, generation: u64) -> bool {
+        // Keeps the reads of the data from moving after the check
+        atomic::fence(Ordering::Acquire);
+        self.generation.load(Ordering::Relaxed) == generation
+    }
+
+    pub fn position(&self, ticket: usize) -> usize {
+        let state = self.state.lock();
+        state.position(ticket)
+    }
+
+    // For ticket guards dropped without being used
+    #[cfg(feature = "debug_ticket_tracking")]
+    pub(crate) fn ticket_dropped(&self, ticket: usize) {
+        self.observe(|observer| observer.ticket_dropped(ticket));
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> LockStats {
+        self.stats.get()
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn avg_queue_depth(&self) -> f64 {
+        self.state.lock().avg_queue_depth
+    }
+}
+
+impl Default for RawQueuedRwLock {
+    fn default() -> RawQueuedRwLock {
+        RawQueuedRwLock::new()
+    }
+}
+
+#[cfg(all(feature = "lock_api", not(loom)))]
+unsafe impl lock_api::RawRwLock for RawQueuedRwLock {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: RawQueuedRwLock = RawQueuedRwLock::new();
+
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock_shared(&self) {
+        self.read();
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        self.try_read()
+    }
+
+    unsafe fn unlock_shared(&self) {
+        self.read_unlock();
+    }
+
+    fn lock_exclusive(&self) {
+        let ticket = self.take_ticket();
+        self.write(ticket);
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        self.try_write_skip_queue()
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        self.write_unlock();
+    }
+}
+
+#[cfg(all(feature = "lock_api", not(loom)))]
+unsafe impl lock_api::RawRwLockDowngrade for RawQueuedRwLock {
+    unsafe fn downgrade(&self) {
+        RawQueuedRwLock::downgrade(self);
+    }
+}
+
+#[cfg(all(feature = "lock_api", feature = "std", not(loom)))]
+unsafe impl lock_api::RawRwLockTimed for RawQueuedRwLock {
+    type Duration = Duration;
+    type Instant = Instant;
+
+    fn try_lock_shared_for(&self, timeout: Duration) -> bool {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.read_until(deadline),
+            None => {
+                self.read();
+                true
+            }
+        }
+    }
+
+    fn try_lock_shared_until(&self, deadline: Instant) -> bool {
+        self.read_until(deadline)
+    }
+
+    fn try_lock_exclusive_for(&self, timeout: Duration) -> bool {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.try_lock_exclusive_until(deadline),
+            None => {
+                lock_api::RawRwLock::lock_exclusive(self);
+                true
+            }
+        }
+    }
+
+    fn try_lock_exclusive_until(&self, deadline: Instant) -> bool {
+        let ticket = self.take_ticket();
+        self.write_until(ticket, deadline)
+    }
+}
+
+#[repr(align(64))]
+struct CachePadded<T>(T);
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+// Top bit of the reader count, set while readers have to go through the
+// state lock to find out whether they can go ahead
+const SLOW: usize = !(usize::MAX >> 1);
+
+struct Readers {
+    count: AtomicUsize,
+    max: usize,
+}
+
+impl Readers {
+    const_fn! {
+        const fn new(max: usize) -> Readers {
+            Readers {
+                count: AtomicUsize::new(0),
+                max,
+            }
+        }
+    }
+
+    // Only succeeds while the slow bit is clear and there's room
+    fn try_add(&self) -> bool {
+        let mut readers = self.count.load(Ordering::Relaxed);
+
+        while readers & SLOW == 0 && readers < self.max {
+            match self.count.compare_exchange_weak(readers,
+                                               readers + 1,
+                                               Ordering::Acquire,
+                                               Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(current) => readers = current,
+            }
+        }
+
+        false
+    }
+
+    // Caller must hold the state lock and have checked it's ok to read
+    fn add(&self) {
+        self.add_many(1);
+    }
+
+    fn add_many(&self, n: usize) {
+        self.count.fetch_add(n, Ordering::Acquire);
+    }
+
+    // Returns the count from before, slow bit included
+    fn remove(&self) -> usize {
+        self.count.fetch_sub(1, Ordering::Release)
+    }
+
+    fn count(&self) -> usize {
+        self.count.load(Ordering::Acquire) & !SLOW
+    }
+
+    fn full(&self) -> bool {
+        self.count() >= self.max
+    }
+
+    // Caller must hold the state lock
+    fn set_slow(&self, slow: bool) {
+        if slow {
+            self.count.fetch_or(SLOW, Ordering::AcqRel);
+        } else {
+            self.count.fetch_and(!SLOW, Ordering::Release);
+        }
     }
 }
 
 struct State {
+    fairness: FairnessMode,
     writer: bool,
-    readers: usize,
+    // Ticket of the upgradable reader, if there is one
+    upgradable: Option<usize>,
     next_ticket: usize,
     total_tickets: usize,
+    // Tickets given up before their turn came, skipped once they're next
+    cancelled: BTreeSet<usize>,
+    // Number of blocked readers by the ticket that was about to be handed out
+    // when they started waiting, only used in Fifo and Alternating modes
+    waiting_readers: BTreeMap<usize, usize>,
+    // In Alternating mode, the ticket waiting readers are let in before
+    turn_end: usize,
+    // Tasks waiting on a writer to leave, keyed so they can be replaced
+    #[cfg(feature = "async")]
+    read_wakers: BTreeMap<usize, Waker>,
+    #[cfg(feature = "async")]
+    next_waker_key: usize,
+    // Tasks waiting on their ticket, keyed by it
+    #[cfg(feature = "async")]
+    write_wakers: BTreeMap<usize, Waker>,
+    // Task of the active writer waiting on readers to leave
+    #[cfg(feature = "async")]
+    drain_waker: Option<Waker>,
+    // Threads blocked in read, and how many of them write_unlock has already
+    // counted as readers and only need to wake up and leave
+    parked_readers: usize,
+    admitted_readers: usize,
+    // Threads in wait_idle, readers keep to the slow path while there are
+    // any so the last one out knows to wake them
+    idle_waiters: usize,
+    // Readers let in while writers were queued since a writer last had the
+    // lock, only used in WriteRatio mode
+    jumped_readers: usize,
+    // Threads in write_priority waiting for the lock, they go ahead of every
+    // ticket and keep new readers out
+    priority_writers: usize,
+    // Priority writes taken so far, they don't use a ticket but are still
+    // counted as requested by write_counts. Wraps on overflow
+    priority_writes: usize,
+    // Thread holding the lock through write_reentrant and how many times
+    #[cfg(feature = "std")]
+    owner: Option<(ThreadId, usize)>,
+    // Moving average of queue_len, updated whenever a ticket is taken or
+    // retired
+    #[cfg(feature = "stats")]
+    avg_queue_depth: f64,
 }
 
 impl State {
-    fn new() -> State {
+    const fn new(fairness: FairnessMode) -> State {
         State {
+            fairness,
             writer: false,
-            readers: 0,
+            upgradable: None,
             next_ticket: 0,
             total_tickets: 0,
+            cancelled: BTreeSet::new(),
+            waiting_readers: BTreeMap::new(),
+            turn_end: 0,
+            #[cfg(feature = "async")]
+            read_wakers: BTreeMap::new(),
+            #[cfg(feature = "async")]
+            next_waker_key: 0,
+            #[cfg(feature = "async")]
+            write_wakers: BTreeMap::new(),
+            #[cfg(feature = "async")]
+            drain_waker: None,
+            parked_readers: 0,
+            admitted_readers: 0,
+            idle_waiters: 0,
+            jumped_readers: 0,
+            priority_writers: 0,
+            priority_writes: 0,
+            #[cfg(feature = "std")]
+            owner: None,
+            #[cfg(feature = "stats")]
+            avg_queue_depth: 0.0,
         }
     }
 
-    fn add_reader(&mut self) {
-        self.readers += 1;
-    }
-
-    fn remove_reader(&mut self) {
-        self.readers -= 1;
-    }
-
-    fn has_readers(&self) -> bool {
-        self.readers != 0
+    fn add_writer(&mut self) {
+        self.advance_ticket();
+        self.writer = true;
+        self.jumped_readers = 0;
     }
 
-    fn add_writer(&mut self) {
-        self.next_ticket += 1;
+    // Same as add_writer for a priority writer, which has no ticket to use up
+    fn add_priority_writer(&mut self) {
+        self.priority_writers -= 1;
+        self.priority_writes = self.priority_writes.wrapping_add(1);
         self.writer = true;
+        self.jumped_readers = 0;
     }
 
     fn remove_writer(&mut self) {
@@ -138,17 +1439,390 @@ impl State {
         self.writer
     }
 
+    // Upgradable readers use up a ticket the same as a writer, the flag then
+    // keeps the next writer from going ahead until they're done
+    fn add_upgradable(&mut self, ticket: usize) {
+        self.advance_ticket();
+        self.upgradable = Some(ticket);
+        self.jumped_readers = 0;
+    }
+
+    fn remove_upgradable(&mut self) {
+        self.upgradable = None;
+    }
+
+    fn has_upgradable(&self) -> bool {
+        self.upgradable.is_some()
+    }
+
+    // Returns the upgradable reader's ticket
+    fn upgrade(&mut self) -> usize {
+        self.writer = true;
+        self.upgradable.take().expect("upgrade without an upgradable read lock")
+    }
+
     fn take_ticket(&mut self) -> usize {
         let ticket = self.total_tickets;
-        self.total_tickets += 1;
+        self.total_tickets = self.total_tickets.wrapping_add(1);
+        self.sample_queue_depth();
         ticket
     }
 
+    fn cancel_ticket(&mut self, ticket: usize) {
+        if self.is_next(ticket) {
+            self.advance_ticket();
+        } else {
+            self.cancelled.insert(ticket);
+            self.sample_queue_depth();
+        }
+    }
+
+    fn advance_ticket(&mut self) {
+        self.next_ticket = self.next_ticket.wrapping_add(1);
+
+        while self.cancelled.remove(&self.next_ticket) {
+            self.next_ticket = self.next_ticket.wrapping_add(1);
+        }
+
+        self.sample_queue_depth();
+    }
+
+    #[cfg(feature = "stats")]
+    fn sample_queue_depth(&mut self) {
+        self.avg_queue_depth += (self.queue_len() as f64 - self.avg_queue_depth) * QUEUE_DEPTH_WEIGHT;
+    }
+
+    #[cfg(not(feature = "stats"))]
+    fn sample_queue_depth(&mut self) {}
+
+    #[cfg(feature = "async")]
+    fn take_waker_key(&mut self) -> usize {
+        let key = self.next_waker_key;
+        self.next_waker_key += 1;
+        key
+    }
+
+    // Only the task holding the next ticket can make progress so the rest
+    // of the writers are left alone
+    fn wake_waiters(&mut self) {
+        #[cfg(feature = "async")]
+        for (_, waker) in mem::take(&mut self.read_wakers) {
+            waker.wake();
+        }
+
+        #[cfg(feature = "async")]
+        if let Some(waker) = self.write_wakers.remove(&self.next_ticket) {
+            waker.wake();
+        }
+    }
+
+    fn wake_drain(&mut self) {
+        #[cfg(feature = "async")]
+        if let Some(waker) = self.drain_waker.take() {
+            waker.wake();
+        }
+    }
+
+    // New readers wait behind queued writers as well as the active one so a
+    // steady stream of readers can't keep a writer out, unless readers are
+    // preferred
+    fn blocks_readers(&self) -> bool {
+        match self.fairness {
+            FairnessMode::ReaderPreferring => self.has_writer(),
+            FairnessMode::WriteRatio(readers) => {
+                self.has_writer() || (!self.queue_empty() && self.jumped_readers >= readers)
+            }
+            FairnessMode::Fifo | FairnessMode::WriterPreferring | FairnessMode::Alternating => {
+                self.has_writer() || !self.queue_empty()
+            }
+        }
+    }
+
+    // Queued writers wait for a moment with no readers rather than keeping
+    // new ones out
+    fn prefers_readers(&self) -> bool {
+        match self.fairness {
+            FairnessMode::ReaderPreferring | FairnessMode::WriteRatio(_) => true,
+            FairnessMode::Fifo | FairnessMode::WriterPreferring | FairnessMode::Alternating => false,
+        }
+    }
+
+    // Only tickets handed out before the reader started waiting are ahead
+    fn blocks_reader_at(&self, place: usize) -> bool {
+        self.has_writer() || self.priority_writers != 0 || before(self.next_ticket, place)
+    }
+
+    fn blocks_writer(&self, ticket: usize) -> bool {
+        self.has_writer() || self.has_upgradable() || !self.is_next(ticket) ||
+        self.priority_writers != 0 ||
+        self.waiting_readers.keys().any(|&place| !before(ticket, place))
+    }
+
+    // Gives a blocked reader a place in the queue so writers that come along
+    // later wait for it, if the mode calls for it. Alternating readers share
+    // the place of the first one still waiting for its writers to finish
+    fn wait_to_read(&mut self) -> Option<usize> {
+        let place = match self.fairness {
+            FairnessMode::Fifo => self.total_tickets,
+            FairnessMode::Alternating => {
+                if !before(self.next_ticket, self.turn_end) {
+                    self.turn_end = self.total_tickets;
+                }
+                self.turn_end
+            }
+            FairnessMode::ReaderPreferring | FairnessMode::WriterPreferring | FairnessMode::WriteRatio(_) => {
+                return None
+            }
+        };
+
+        *self.waiting_readers.entry(place).or_insert(0) += 1;
+        Some(place)
+    }
+
+    fn stop_waiting(&mut self, place: usize) {
+        if let Some(count) = self.waiting_readers.get_mut(&place) {
+            *count -= 1;
+            if *count == 0 {
+                self.waiting_readers.remove(&place);
+            }
+        }
+    }
+
     fn is_next(&self, ticket: usize) -> bool {
         self.next_ticket == ticket
     }
 
+    // Tickets taken but not yet used up, not counting ones given up on, and
+    // priority writers
+    fn queue_len(&self) -> usize {
+        self.total_tickets
+            .wrapping_sub(self.next_ticket)
+            .saturating_sub(self.cancelled.len()) + self.priority_writers
+    }
+
+    // Tickets still to be served before this one
+    fn position(&self, ticket: usize) -> usize {
+        if !before(self.next_ticket, ticket) {
+            return 0;
+        }
+
+        let cancelled = self.cancelled.iter().filter(|&&cancelled| before(cancelled, ticket)).count();
+        ticket.wrapping_sub(self.next_ticket) - cancelled
+    }
+
     fn queue_empty(&self) -> bool {
-        self.next_ticket == self.total_tickets - 1
+        self.next_ticket == self.total_tickets && self.priority_writers == 0
+    }
+}
+
+// Whether ticket a was handed out before b. The counters wrap so tickets are
+// compared by their distance, which works as long as no two tickets still in
+// use are more than half the range apart
+fn before(a: usize, b: usize) -> bool {
+    (a.wrapping_sub(b) as isize) < 0
+}
+
+// The spinning condvar without std has no wakeups to count
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::RawQueuedRwLock;
+
+    // Starts the tickets a few short of wrapping around
+    fn near_wrap() -> RawQueuedRwLock {
+        let lock = RawQueuedRwLock::with_spin_count(0);
+        {
+            let mut state = lock.state.lock();
+            state.next_ticket = usize::MAX - 2;
+            state.total_tickets = usize::MAX - 2;
+        }
+        lock
+    }
+
+    #[test]
+    fn last_reader_wakes_draining_writer() {
+        for i in 0..1000 {
+            let lock = RawQueuedRwLock::with_spin_count(0);
+            let (tx, rx) = mpsc::channel();
+            lock.read();
+
+            thread::scope(|s| {
+                s.spawn(|| {
+                    let ticket = lock.take_ticket();
+                    lock.write(ticket);
+                    tx.send(()).unwrap();
+                    lock.write_unlock();
+                });
+
+                // Leave at different points of the writer getting ready
+                for _ in 0..i % 8 {
+                    thread::yield_now();
+                }
+                lock.read_unlock();
+
+                rx.recv_timeout(Duration::from_secs(5)).expect("writer missed the last reader leaving");
+            });
+        }
+    }
+
+    #[test]
+    fn write_unlock_admits_parked_readers() {
+        const READERS: usize = 8;
+
+        let lock = RawQueuedRwLock::with_spin_count(0);
+        let (tx, rx) = mpsc::channel();
+        let ticket = lock.take_ticket();
+        lock.write(ticket);
+
+        thread::scope(|s| {
+            for _ in 0..READERS {
+                let tx = tx.clone();
+                let lock = &lock;
+                s.spawn(move || {
+                    lock.read();
+                    tx.send(()).unwrap();
+                });
+            }
+
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(lock.state.lock().parked_readers, READERS);
+
+            // Counted before any of them have woken up
+            lock.write_unlock();
+            assert_eq!(lock.reader_count(), READERS);
+
+            for _ in 0..READERS {
+                rx.recv_timeout(Duration::from_secs(5)).expect("parked reader never woke");
+            }
+        });
+
+        let state = lock.state.lock();
+        assert_eq!((state.parked_readers, state.admitted_readers), (0, 0));
+        drop(state);
+
+        for _ in 0..READERS {
+            lock.read_unlock();
+        }
+        assert!(lock.try_write_skip_queue());
+    }
+
+    #[test]
+    fn write_unlock_keeps_parked_readers_behind_tickets() {
+        let lock = RawQueuedRwLock::with_spin_count(0);
+        let first = lock.take_ticket();
+        lock.write(first);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                lock.read();
+                lock.read_unlock();
+            });
+
+            thread::sleep(Duration::from_millis(50));
+            let second = lock.take_ticket();
+            lock.write_unlock();
+
+            // With a writer queued the reader comes in the usual way, behind
+            // any writer ahead of it
+            assert_eq!(lock.state.lock().admitted_readers, 0);
+            lock.write(second);
+            lock.write_unlock();
+        });
+    }
+
+    #[test]
+    fn write_unlock_wakes_next_ticket() {
+        const WRITERS: usize = 64;
+
+        let lock = RawQueuedRwLock::with_spin_count(0);
+        let ticket = lock.take_ticket();
+        lock.write(ticket);
+
+        thread::scope(|s| {
+            for _ in 0..WRITERS {
+                let ticket = lock.take_ticket();
+                let lock = &lock;
+                s.spawn(move || {
+                    lock.write(ticket);
+                    lock.write_unlock();
+                });
+            }
+
+            thread::sleep(Duration::from_millis(50));
+            lock.write_unlock();
+        });
+
+        // Waking the whole queue on every unlock would be WRITERS^2 / 2
+        let wakeups = lock.wakeups.load(Ordering::Relaxed);
+        assert!(wakeups <= WRITERS * 4, "{} wakeups for {} writers", wakeups, WRITERS);
+    }
+
+    #[test]
+    fn tickets_wrap_in_order() {
+        const WRITERS: usize = 6;
+
+        let lock = near_wrap();
+        let (tx, rx) = mpsc::channel();
+        let tickets: Vec<usize> = (0..WRITERS).map(|_| lock.take_ticket()).collect();
+        assert_eq!(tickets[3], 0);
+        assert_eq!(lock.queue_len(), WRITERS);
+        assert_eq!(lock.position(tickets[5]), 5);
+
+        lock.cancel_ticket(tickets[1]);
+        assert_eq!(lock.position(tickets[5]), 4);
+
+        thread::scope(|s| {
+            // Started backwards so they'd go in the wrong order if they could
+            for (i, &ticket) in tickets.iter().enumerate().rev().filter(|&(i, _)| i != 1) {
+                let tx = tx.clone();
+                let lock = &lock;
+                s.spawn(move || {
+                    lock.write(ticket);
+                    tx.send(i).unwrap();
+                    lock.write_unlock();
+                });
+            }
+        });
+
+        let order: Vec<usize> = rx.try_iter().collect();
+        assert_eq!(order, [0, 2, 3, 4, 5]);
+        assert_eq!(lock.queue_len(), 0);
+    }
+
+    #[test]
+    fn reader_place_survives_wrap() {
+        let lock = near_wrap();
+        let first = lock.take_ticket();
+        lock.write(first);
+
+        // Waits behind the three tickets that wrap once it's parked
+        let tickets: Vec<usize> = (0..3).map(|_| lock.take_ticket()).collect();
+        let (tx, rx) = mpsc::channel();
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                lock.read();
+                tx.send(()).unwrap();
+                lock.read_unlock();
+            });
+            thread::sleep(Duration::from_millis(20));
+
+            // A writer after the reader's place has to wait for it
+            let later = lock.take_ticket();
+            lock.write_unlock();
+            for ticket in tickets {
+                lock.write(ticket);
+                assert!(rx.try_recv().is_err());
+                lock.write_unlock();
+            }
+
+            rx.recv_timeout(Duration::from_secs(5)).expect("reader stuck behind the wrap");
+            lock.write(later);
+            lock.write_unlock();
+        });
     }
 }
@@ -0,0 +1,178 @@
+// Copyright (c) 2016 Travis Watkins
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use alloc::sync::Arc;
+use std::fmt;
+use std::marker::PhantomData;
+
+use nopoison::QueuedRwLockNoPoison;
+use sync::hint;
+use sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+/// A value that's read far more often than it's changed. Readers get an
+/// `Arc` of the current value without taking a lock, writers make a new
+/// value and swap it in, in the order they called `write`.
+///
+/// Memory is reclaimed without hazard pointers. A reader only ever holds on
+/// to the value for as long as it takes to bump the `Arc`'s count, which is
+/// tracked in one of two reader counts picked by the current epoch. A writer
+/// swaps the value, moves the epoch on and waits for the count of the
+/// previous epoch to drain before letting go of its reference to the old
+/// value. Anyone that got an `Arc` of the old value in time keeps it alive,
+/// it's freed when the last of them is dropped.
+pub struct RcuCell<T> {
+    value: AtomicPtr<T>,
+    epoch: AtomicUsize,
+    readers: [AtomicUsize; 2],
+    writers: QueuedRwLockNoPoison<()>,
+    _owns: PhantomData<Arc<T>>,
+}
+
+impl<T> RcuCell<T> {
+    pub fn new(value: T) -> RcuCell<T> {
+        RcuCell {
+            value: AtomicPtr::new(Arc::into_raw(Arc::new(value)) as *mut T),
+            epoch: AtomicUsize::new(0),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            writers: QueuedRwLockNoPoison::new(()),
+            _owns: PhantomData,
+        }
+    }
+
+    /// The current value, never waits for writers.
+    pub fn read(&self) -> Arc<T> {
+        // Only counts once the epoch is known not to have moved on before we
+        // were, otherwise a writer two epochs on could be waiting on the
+        // other count while we load the value it frees. Everything is SeqCst
+        // so a writer that sees our count at zero has swapped in the new
+        // value before we load it
+        let readers = loop {
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            let readers = &self.readers[epoch & 1];
+            readers.fetch_add(1, Ordering::SeqCst);
+
+            if self.epoch.load(Ordering::SeqCst) == epoch {
+                break readers;
+            }
+            readers.fetch_sub(1, Ordering::SeqCst);
+        };
+
+        let value = self.value.load(Ordering::SeqCst);
+        let value = unsafe {
+            Arc::increment_strong_count(value);
+            Arc::from_raw(value)
+        };
+
+        readers.fetch_sub(1, Ordering::SeqCst);
+        value
+    }
+
+    /// Swaps in the value `f` makes from the current one. Writers go one at
+    /// a time in the order they called this, readers aren't held up.
+    ///
+    /// Returns once no new reader can see the old value.
+    pub fn write<F>(&self, f: F)
+        where F: FnOnce(&T) -> T
+    {
+        let _writer = self.writers.write();
+
+        // Only writers change the value and we're the only one
+        let old = self.value.load(Ordering::SeqCst);
+        let new = Arc::into_raw(Arc::new(f(unsafe { &*old }))) as *mut T;
+        self.value.store(new, Ordering::SeqCst);
+
+        let epoch = self.epoch.fetch_add(1, Ordering::SeqCst);
+        while self.readers[epoch & 1].load(Ordering::SeqCst) != 0 {
+            hint::spin_loop();
+        }
+
+        drop(unsafe { Arc::from_raw(old) });
+    }
+}
+
+unsafe impl<T: Send + Sync> Send for RcuCell<T> {}
+unsafe impl<T: Send + Sync> Sync for RcuCell<T> {}
+
+impl<T: Default> Default for RcuCell<T> {
+    fn default() -> RcuCell<T> {
+        RcuCell::new(T::default())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RcuCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RcuCell")
+            .field("value", &self.read())
+            .finish()
+    }
+}
+
+impl<T> Drop for RcuCell<T> {
+    fn drop(&mut self) {
+        drop(unsafe { Arc::from_raw(self.value.load(Ordering::Relaxed)) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    use RcuCell;
+
+    #[test]
+    fn read_write() {
+        let cell = RcuCell::new(1);
+        let before = cell.read();
+
+        cell.write(|x| x + 1);
+        assert_eq!(*before, 1);
+        assert_eq!(*cell.read(), 2);
+    }
+
+    #[test]
+    fn old_values_dropped_once() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Counted(usize);
+
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let cell = RcuCell::new(Counted(0));
+        let kept = cell.read();
+
+        thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        cell.write(|old| Counted(old.0 + 1));
+                    }
+                });
+            }
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let value = cell.read();
+                        assert!(value.0 <= 200);
+                    }
+                });
+            }
+        });
+
+        assert_eq!(cell.read().0, 200);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 199);
+
+        drop(kept);
+        drop(cell);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 201);
+    }
+}
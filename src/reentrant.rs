@@ -0,0 +1,135 @@
+// Copyright (c) 2016 Travis Watkins
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+use raw::RawQueuedRwLock;
+
+/// A write-only `QueuedRwLock` that the thread holding it can lock again
+/// without deadlocking, it's only released once every guard is dropped.
+/// Other threads still queue for it in order.
+///
+/// Guards on the same thread overlap, so they only hand out `&T`. Anything
+/// that needs changing goes in a `Cell` or `RefCell`, which is what makes
+/// sure a nested guard can't see a value the outer one is part way through
+/// changing. Since only one thread at a time ever has access, `T` doesn't
+/// need to be `Sync` for the lock to be.
+///
+/// There's no poisoning, a panic just releases the lock.
+pub struct QueuedRwLockReentrant<T> {
+    inner: RawQueuedRwLock,
+    data: T,
+}
+
+unsafe impl<T: Send> Send for QueuedRwLockReentrant<T> {}
+unsafe impl<T: Send> Sync for QueuedRwLockReentrant<T> {}
+
+impl<T> QueuedRwLockReentrant<T> {
+    const_fn! {
+        pub const fn new(data: T) -> QueuedRwLockReentrant<T> {
+            QueuedRwLockReentrant {
+                inner: RawQueuedRwLock::new(),
+                data,
+            }
+        }
+    }
+
+    pub fn write(&self) -> QueuedRwLockReentrantGuard<'_, T> {
+        self.inner.write_reentrant();
+        QueuedRwLockReentrantGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.data
+    }
+}
+
+impl<T: Default> Default for QueuedRwLockReentrant<T> {
+    fn default() -> QueuedRwLockReentrant<T> {
+        QueuedRwLockReentrant::new(T::default())
+    }
+}
+
+impl<T> fmt::Debug for QueuedRwLockReentrant<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("QueuedRwLockReentrant").finish_non_exhaustive()
+    }
+}
+
+/// Has to be dropped on the thread that made it, the depth is kept per
+/// thread.
+#[must_use]
+pub struct QueuedRwLockReentrantGuard<'a, T: 'a> {
+    lock: &'a QueuedRwLockReentrant<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+unsafe impl<'a, T: Sync> Sync for QueuedRwLockReentrantGuard<'a, T> {}
+
+impl<'a, T> Deref for QueuedRwLockReentrantGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.lock.data
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for QueuedRwLockReentrantGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T> Drop for QueuedRwLockReentrantGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.inner.write_reentrant_unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::thread;
+    use std::time::Duration;
+
+    use QueuedRwLock;
+
+    #[test]
+    fn nested_write() {
+        let lock = QueuedRwLock::new_reentrant(RefCell::new(Vec::new()));
+
+        let outer = lock.write();
+        outer.borrow_mut().push(1);
+        {
+            let inner = lock.write();
+            inner.borrow_mut().push(2);
+        }
+        outer.borrow_mut().push(3);
+
+        thread::scope(|s| {
+            let other = s.spawn(|| lock.write().borrow_mut().push(4));
+            thread::sleep(Duration::from_millis(10));
+            assert!(!other.is_finished());
+
+            // Only the last guard lets the other thread in
+            drop(outer);
+            other.join().unwrap();
+        });
+
+        assert_eq!(lock.into_inner().into_inner(), [1, 2, 3, 4]);
+    }
+}
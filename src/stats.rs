@@ -0,0 +1,73 @@
+// Copyright (c) 2016 Travis Watkins
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use observer::LockObserver;
+
+/// Totals for everything a lock has done since it was made, from
+/// `RawQueuedRwLock::stats`.
+///
+/// Wait times are always zero for the async methods and without the `std`
+/// feature, the same as for a `LockObserver`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LockStats {
+    /// Read locks taken, including upgradable ones.
+    pub reads: u64,
+    /// Write locks taken, including upgrades.
+    pub writes: u64,
+    /// Time spent waiting to take those write locks.
+    pub write_wait: Duration,
+    /// Most tickets that were waiting at once.
+    pub max_queue_len: usize,
+}
+
+// Counted as just another observer, but one every lock has. The counters
+// don't order anything so they can all be relaxed
+pub struct Stats {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    write_wait_nanos: AtomicU64,
+    max_queue_len: AtomicUsize,
+}
+
+impl Stats {
+    pub const fn new() -> Stats {
+        Stats {
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            write_wait_nanos: AtomicU64::new(0),
+            max_queue_len: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn queued(&self, queue_len: usize) {
+        self.max_queue_len.fetch_max(queue_len, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> LockStats {
+        LockStats {
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            write_wait: Duration::from_nanos(self.write_wait_nanos.load(Ordering::Relaxed)),
+            max_queue_len: self.max_queue_len.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl LockObserver for Stats {
+    fn read_acquired(&self, _wait: Duration) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn write_acquired(&self, _ticket: usize, wait: Duration) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.write_wait_nanos.fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
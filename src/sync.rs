@@ -0,0 +1,161 @@
+// Copyright (c) 2016 Travis Watkins
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The primitives RawQueuedRwLock is built on. With std these wrap the std
+// ones, without it they spin, and under loom they're loom's so the lock can
+// be model checked.
+
+#[cfg(any(feature = "std", loom))]
+pub use self::os::{Condvar, Mutex, MutexGuard};
+#[cfg(not(any(feature = "std", loom)))]
+pub use self::spin::{Condvar, Mutex, MutexGuard};
+
+#[cfg(not(loom))]
+pub use std::{hint, sync::atomic};
+#[cfg(loom)]
+pub use loom::{hint, sync::atomic};
+
+#[cfg(any(feature = "std", loom))]
+mod os {
+    use std::time::Duration;
+
+    #[cfg(not(loom))]
+    use std::sync;
+    #[cfg(loom)]
+    use loom::sync;
+
+    pub use self::sync::MutexGuard;
+
+    pub struct Mutex<T>(sync::Mutex<T>);
+
+    impl<T> Mutex<T> {
+        const_fn! {
+            pub const fn new(data: T) -> Mutex<T> {
+                Mutex(sync::Mutex::new(data))
+            }
+        }
+
+        pub fn lock(&self) -> MutexGuard<'_, T> {
+            self.0.lock().unwrap()
+        }
+    }
+
+    pub struct Condvar(sync::Condvar);
+
+    impl Condvar {
+        const_fn! {
+            pub const fn new() -> Condvar {
+                Condvar(sync::Condvar::new())
+            }
+        }
+
+        pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+            self.0.wait(guard).unwrap()
+        }
+
+        pub fn wait_timeout<'a, T>(&self, guard: MutexGuard<'a, T>, dur: Duration) -> MutexGuard<'a, T> {
+            self.0.wait_timeout(guard, dur).unwrap().0
+        }
+
+        pub fn notify_all(&self) {
+            self.0.notify_all();
+        }
+    }
+}
+
+#[cfg(not(any(feature = "std", loom)))]
+mod spin {
+    use std::cell::UnsafeCell;
+    use std::hint;
+    use std::ops::{Deref, DerefMut};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    pub struct Mutex<T> {
+        locked: AtomicBool,
+        data: UnsafeCell<T>,
+    }
+
+    unsafe impl<T: Send> Send for Mutex<T> {}
+    unsafe impl<T: Send> Sync for Mutex<T> {}
+
+    impl<T> Mutex<T> {
+        pub const fn new(data: T) -> Mutex<T> {
+            Mutex {
+                locked: AtomicBool::new(false),
+                data: UnsafeCell::new(data),
+            }
+        }
+
+        pub fn lock(&self) -> MutexGuard<'_, T> {
+            while self.locked
+                .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_err() {
+                // Wait for it to look free before trying again so we aren't
+                // taking the cache line away from whoever holds it
+                while self.locked.load(Ordering::Relaxed) {
+                    hint::spin_loop();
+                }
+            }
+
+            MutexGuard { mutex: self }
+        }
+    }
+
+    pub struct MutexGuard<'a, T: 'a> {
+        mutex: &'a Mutex<T>,
+    }
+
+    impl<'a, T> Deref for MutexGuard<'a, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            unsafe { &*self.mutex.data.get() }
+        }
+    }
+
+    impl<'a, T> DerefMut for MutexGuard<'a, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            unsafe { &mut *self.mutex.data.get() }
+        }
+    }
+
+    impl<'a, T> Drop for MutexGuard<'a, T> {
+        fn drop(&mut self) {
+            self.mutex.locked.store(false, Ordering::Release);
+        }
+    }
+
+    // Waiters spin until the generation moves on. It's read before the mutex
+    // is let go and only bumped with it held, so a notify can't slip in
+    // between unnoticed
+    pub struct Condvar {
+        generation: AtomicUsize,
+    }
+
+    impl Condvar {
+        pub const fn new() -> Condvar {
+            Condvar { generation: AtomicUsize::new(0) }
+        }
+
+        pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+            let mutex = guard.mutex;
+            let generation = self.generation.load(Ordering::Relaxed);
+            drop(guard);
+
+            while self.generation.load(Ordering::Acquire) == generation {
+                hint::spin_loop();
+            }
+
+            mutex.lock()
+        }
+
+        pub fn notify_all(&self) {
+            self.generation.fetch_add(1, Ordering::Release);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright (c) 2016 Travis Watkins
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Model checks of RawQueuedRwLock, run with
+//
+//     RUSTFLAGS="--cfg loom" cargo test --test loom --release
+//
+// Spinning is turned off so loom only has the condvars to explore.
+
+#![cfg(loom)]
+
+extern crate loom;
+extern crate queuedrwlock;
+
+use loom::sync::atomic::{AtomicUsize, Ordering};
+use loom::sync::Arc;
+use loom::thread;
+
+use queuedrwlock::{FairnessMode, RawQueuedRwLock};
+
+#[test]
+fn last_reader_wakes_draining_writer() {
+    loom::model(|| {
+        let lock = Arc::new(RawQueuedRwLock::with_spin_count(0));
+        lock.read();
+
+        let writer = {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                let ticket = lock.take_ticket();
+                lock.write(ticket);
+                lock.write_unlock();
+            })
+        };
+
+        lock.read_unlock();
+        writer.join().unwrap();
+        assert!(lock.try_write_skip_queue());
+    });
+}
+
+#[test]
+fn writers_go_in_ticket_order() {
+    loom::model(|| {
+        let lock = Arc::new(RawQueuedRwLock::with_spin_count(0));
+        let order = Arc::new(AtomicUsize::new(0));
+
+        let first = lock.take_ticket();
+        let second = lock.take_ticket();
+
+        let handles: Vec<_> = vec![(second, 1), (first, 0)].into_iter().map(|(ticket, expected)| {
+            let lock = lock.clone();
+            let order = order.clone();
+            thread::spawn(move || {
+                lock.write(ticket);
+                assert_eq!(order.fetch_add(1, Ordering::Relaxed), expected);
+                lock.write_unlock();
+            })
+        }).collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(lock.queue_len(), 0);
+    });
+}
+
+#[test]
+fn reader_and_writer_exclude_each_other() {
+    loom::model(|| {
+        let lock = Arc::new(RawQueuedRwLock::with_fairness(FairnessMode::Fifo));
+        let writing = Arc::new(AtomicUsize::new(0));
+
+        let writer = {
+            let lock = lock.clone();
+            let writing = writing.clone();
+            thread::spawn(move || {
+                let ticket = lock.take_ticket();
+                lock.write(ticket);
+                writing.store(1, Ordering::Relaxed);
+                writing.store(0, Ordering::Relaxed);
+                lock.write_unlock();
+            })
+        };
+
+        lock.read();
+        assert_eq!(writing.load(Ordering::Relaxed), 0);
+        lock.read_unlock();
+
+        writer.join().unwrap();
+    });
+}
+
+#[test]
+fn last_reader_wakes_wait_idle() {
+    loom::model(|| {
+        let lock = Arc::new(RawQueuedRwLock::with_spin_count(0));
+        lock.read();
+
+        let waiter = {
+            let lock = lock.clone();
+            thread::spawn(move || lock.wait_idle())
+        };
+
+        lock.read_unlock();
+        waiter.join().unwrap();
+    });
+}